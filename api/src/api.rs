@@ -7,9 +7,43 @@ rpc_api! {
 
     rpc init_genesis_state(InitStateRequest) -> InitStateResponse;
 
+    rpc migrate_state(MigrateStateRequest) -> MigrateStateResponse;
+
     rpc create(CreateTokenRequest) -> CreateTokenResponse;
 
+    rpc deploy_contract(DeployContractRequest) -> DeployContractResponse;
+
     rpc transfer(TransferTokenRequest) -> TransferTokenResponse;
 
+    rpc call_contract(CallContractRequest) -> CallContractResponse;
+
     rpc get_balance(GetBalanceRequest) -> GetBalanceResponse;
+
+    rpc static_call(StaticCallRequest) -> StaticCallResponse;
+
+    rpc send_raw_transaction(SendRawTransactionRequest) -> SendRawTransactionResponse;
+
+    rpc submit_batch(SubmitBatchRequest) -> SubmitBatchResponse;
+
+    rpc get_transaction_by_hash(GetTransactionByHashRequest) -> GetTransactionByHashResponse;
+
+    rpc get_transaction_receipt(GetTransactionReceiptRequest) -> GetTransactionReceiptResponse;
+
+    rpc get_logs(GetLogsRequest) -> GetLogsResponse;
+
+    rpc get_block_number(GetBlockNumberRequest) -> GetBlockNumberResponse;
+
+    rpc get_block_by_number(GetBlockByNumberRequest) -> GetBlockByNumberResponse;
+
+    rpc get_state_root(GetStateRootRequest) -> GetStateRootResponse;
+
+    rpc get_proof(GetProofRequest) -> GetProofResponse;
+
+    rpc snapshot(SnapshotRequest) -> SnapshotResponse;
+
+    rpc revert_to_snapshot(RevertToSnapshotRequest) -> RevertToSnapshotResponse;
+
+    rpc dump_state(DumpStateRequest) -> DumpStateResponse;
+
+    rpc load_state(LoadStateRequest) -> LoadStateResponse;
 }
@@ -7,6 +7,9 @@ extern crate hex;
 #[macro_use]
 extern crate lazy_static;
 extern crate rand;
+extern crate rlp;
+extern crate secp256k1;
+extern crate sha3;
 extern crate tokio_core;
 
 #[macro_use]
@@ -20,6 +23,12 @@ use clap::{App, Arg};
 use futures::future::Future;
 
 use rand::{thread_rng, Rng};
+use rlp::RlpStream;
+use secp256k1::{sign, Message, PublicKey, SecretKey};
+use sha3::{Digest, Keccak256};
+
+use std::cell::RefCell;
+use std::sync::Mutex;
 
 use ekiden_rpc_client::create_client_rpc;
 use evm_api::with_api;
@@ -31,8 +40,19 @@ with_api! {
 // Initial supply of tokens.
 const INITIAL_SUPPLY: u64 = 1_000_000;
 
-// Address of token creator. Can be anything but must parse to a valid Ethereum address (160-bit).
-const TOKEN_CREATOR: &str = "0x4e4f41484e4f41484e4f41484e4f41484e4f4148";
+// Chain id chosen at genesis, which request signatures commit to.
+const CHAIN_ID: u64 = 1337;
+
+// Gas limit of the token creation. Signatures cover the gas limit, so every request sets it
+// rather than defaulting to the block gas limit.
+const CREATE_GAS_LIMIT: u64 = 3_000_000;
+
+// Gas limit of a token transfer. A batch populating OTHER_ACCOUNT_COUNT accounts must fit in the
+// block gas limit of 8,000,000.
+const TRANSFER_GAS_LIMIT: u64 = 75_000;
+
+// Gas limit of deploying and calling the self-destructing contract.
+const CALL_GAS_LIMIT: u64 = 100_000;
 
 // Amount to transfer from this client.
 const TRANSFER_AMOUNT: u64 = 3;
@@ -40,6 +60,59 @@ const TRANSFER_AMOUNT: u64 = 3;
 // Address to transfer tokens to.
 const TRANSFER_TO_ADDR: &str = "0x57415252454e57415252454e57415252454e0000";
 
+// Init code of a contract that self-destructs as soon as it's called, sending its balance to
+// TRANSFER_TO_ADDR.
+const SELF_DESTRUCT_CONTRACT: &str =
+    "601680600b6000396000f37357415252454e57415252454e57415252454e0000ff";
+
+// Balance (in wei) of the self-destructing contract, allocated to the token creator at genesis.
+const SELF_DESTRUCT_VALUE: u64 = 1_000;
+
+// keccak256 of empty code.
+const EMPTY_CODE_HASH: &str = "0xc5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470";
+
+/// An account whose key is held by this client.
+struct Account {
+    key: SecretKey,
+    address: String,
+}
+
+impl Account {
+    /// Generates a new random key.
+    fn generate() -> Self {
+        let key = loop {
+            let mut buf = [0; 32];
+            thread_rng().fill_bytes(&mut buf);
+            if let Ok(key) = SecretKey::parse(&buf) {
+                break key;
+            }
+        };
+
+        // The address is the last 20 bytes of the keccak256 hash of the public key.
+        let public_key = PublicKey::from_secret_key(&key).serialize();
+        let address = String::from("0x") + &hex::encode(&Keccak256::digest(&public_key[1..])[12..]);
+
+        Account {
+            key: key,
+            address: address,
+        }
+    }
+}
+
+lazy_static! {
+    // Token creator, who receives the initial supply.
+    static ref TOKEN_CREATOR: Account = Account::generate();
+
+    // Accounts funded by init, one for each benchmark thread to send transfers from. Each thread
+    // has its own sender so that the nonces of concurrent transfers don't collide.
+    static ref SENDERS: Mutex<Vec<Account>> = Mutex::new(vec![]);
+}
+
+thread_local! {
+    // Sender of this benchmark thread, along with its next nonce.
+    static SENDER: RefCell<Option<(Account, u64)>> = RefCell::new(None);
+}
+
 // Address of created contract (set by init method).
 static mut CONTRACT_ADDR: Option<String> = None;
 
@@ -59,14 +132,150 @@ lazy_static! {
     };
 }
 
+/// Encodes a number as a 32-byte big-endian word.
+fn word(value: u64) -> Vec<u8> {
+    let mut word = vec![0; 24];
+    word.extend_from_slice(&[
+        (value >> 56) as u8,
+        (value >> 48) as u8,
+        (value >> 40) as u8,
+        (value >> 32) as u8,
+        (value >> 24) as u8,
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ]);
+    word
+}
+
+/// Signs `hash` with `key`, as r || s || v, where v is the recovery id.
+fn sign_hash(key: &SecretKey, hash: &[u8]) -> Vec<u8> {
+    let mut message = [0; 32];
+    message.copy_from_slice(hash);
+    let (signature, recovery_id) = sign(&Message::parse(&message), key).unwrap();
+    let mut signature = signature.serialize().to_vec();
+    signature.push(recovery_id.serialize());
+    signature
+}
+
+/// Signs the transaction from `from` calling `to` with `data` as calldata, or deploying a contract
+/// with `data` as init code if `to` is `None`, at a gas price of zero. This is the signature
+/// `send_raw_transaction` would take, which the deploy_contract, call_contract and submit_batch
+/// requests carry.
+fn sign_transaction(
+    from: &Account,
+    nonce: u64,
+    gas_limit: u64,
+    to: Option<&str>,
+    value: u64,
+    data: &[u8],
+) -> Vec<u8> {
+    // The EIP-155 signing hash is keccak256(rlp([nonce, gasPrice, gasLimit, to, value, data,
+    // chainId, 0, 0])).
+    let mut stream = RlpStream::new_list(9);
+    stream.append(&nonce);
+    stream.append(&0u64);
+    stream.append(&gas_limit);
+    match to {
+        Some(to) => stream.append(&hex::decode(&to[2..]).unwrap()),
+        None => stream.append_empty_data(),
+    };
+    stream.append(&value);
+    stream.append(&data);
+    stream.append(&CHAIN_ID);
+    stream.append_empty_data();
+    stream.append_empty_data();
+
+    sign_hash(&from.key, &Keccak256::digest(&stream.out()))
+}
+
+/// Returns the calldata of transfer(address,uint256), as built by the enclave's transfer RPC.
+fn transfer_data(to: &str, amount: u64) -> Vec<u8> {
+    let mut data = Keccak256::digest(b"transfer(address,uint256)")[..4].to_vec();
+    data.extend_from_slice(&[0; 12]);
+    data.extend_from_slice(&hex::decode(&to[2..]).unwrap());
+    data.extend_from_slice(&word(amount));
+    data
+}
+
+/// Returns the nonce of `address`.
+fn account_nonce<Backend>(client: &mut evm::Client<Backend>, address: &str) -> u64
+where
+    Backend: ekiden_rpc_client::backend::ContractClientBackend,
+{
+    client
+        .get_proof({
+            let mut req = evm::GetProofRequest::new();
+            req.set_address(address.to_string());
+            req
+        })
+        .wait()
+        .unwrap()
+        .get_nonce()
+        .parse()
+        .unwrap()
+}
+
+/// Transfers `amount` tokens from `from` to `to`, signing the request with the key of `from`.
+/// `nonce` is the account nonce of `from`.
+fn transfer<Backend>(
+    client: &mut evm::Client<Backend>,
+    from: &Account,
+    nonce: u64,
+    to: &str,
+    amount: u64,
+) where
+    Backend: ekiden_rpc_client::backend::ContractClientBackend,
+{
+    let contract_addr = unsafe { CONTRACT_ADDR.as_ref().unwrap().clone() };
+
+    // The signature covers
+    // keccak256(contract || to || amount || nonce || gas_limit || gas_price || chain_id), with a
+    // gas price of zero.
+    let mut message = hex::decode(&contract_addr[2..]).unwrap();
+    message.extend_from_slice(&hex::decode(&to[2..]).unwrap());
+    message.extend_from_slice(&word(amount));
+    message.extend_from_slice(&word(nonce));
+    message.extend_from_slice(&word(TRANSFER_GAS_LIMIT));
+    message.extend_from_slice(&word(0));
+    message.extend_from_slice(&word(CHAIN_ID));
+    let signature = sign_hash(&from.key, &Keccak256::digest(&message));
+
+    client
+        .transfer({
+            let mut req = evm::TransferTokenRequest::new();
+            req.set_contract_address(contract_addr);
+            req.set_from_address(from.address.clone());
+            req.set_to_address(to.to_string());
+            req.set_amount(amount);
+            req.set_gas_limit(TRANSFER_GAS_LIMIT);
+            req.set_nonce(nonce);
+            req.set_signature(signature);
+            req
+        })
+        .wait()
+        .unwrap();
+}
+
 /// Initializes the ethtoken scenario.
-fn init<Backend>(client: &mut evm::Client<Backend>, _runs: usize, _threads: usize)
+fn init<Backend>(client: &mut evm::Client<Backend>, runs: usize, threads: usize)
 where
     Backend: ekiden_rpc_client::backend::ContractClientBackend,
 {
-    // Initialize empty state.
+    // Initialize state, funding the creator with the balance of the self-destructing contract.
     client
-        .init_genesis_state(evm::InitStateRequest::new())
+        .init_genesis_state({
+            let mut req = evm::InitStateRequest::new();
+            req.mut_alloc().insert(TOKEN_CREATOR.address.clone(), {
+                let mut account = evm::GenesisAccount::new();
+                account.set_balance(SELF_DESTRUCT_VALUE.to_string());
+                account
+            });
+            req.set_chain_id(CHAIN_ID);
+            // The client initializes the chain again on every run.
+            req.set_dev_mode(true);
+            req
+        })
         .wait()
         .unwrap();
 
@@ -76,13 +285,27 @@ where
     // so they aren't specified here.
     println!(
         "Creating token contract with {} initial tokens (creator address {})",
-        INITIAL_SUPPLY, TOKEN_CREATOR
+        INITIAL_SUPPLY, TOKEN_CREATOR.address
     );
+    let mut creator_nonce = account_nonce(client, &TOKEN_CREATOR.address);
+
+    // The signature covers keccak256(initial_supply || nonce || gas_limit || gas_price ||
+    // chain_id), with a gas price of zero.
+    let mut message = word(INITIAL_SUPPLY);
+    message.extend_from_slice(&word(creator_nonce));
+    message.extend_from_slice(&word(CREATE_GAS_LIMIT));
+    message.extend_from_slice(&word(0));
+    message.extend_from_slice(&word(CHAIN_ID));
+    let signature = sign_hash(&TOKEN_CREATOR.key, &Keccak256::digest(&message));
+
     let contract_addr = client
         .create({
             let mut req = evm::CreateTokenRequest::new();
-            req.set_creator_address(TOKEN_CREATOR.to_string());
+            req.set_creator_address(TOKEN_CREATOR.address.clone());
             req.set_initial_supply(INITIAL_SUPPLY);
+            req.set_gas_limit(CREATE_GAS_LIMIT);
+            req.set_nonce(creator_nonce);
+            req.set_signature(signature);
             req
         })
         .wait()
@@ -90,6 +313,8 @@ where
         .get_contract_address()
         .to_string();
 
+    creator_nonce += 1;
+
     unsafe {
         CONTRACT_ADDR = Some(contract_addr.clone());
     }
@@ -100,40 +325,170 @@ where
         .get_balance({
             let mut req = evm::GetBalanceRequest::new();
             req.set_contract_address(contract_addr.clone());
-            req.set_address(TOKEN_CREATOR.to_string());
+            req.set_address(TOKEN_CREATOR.address.clone());
             req
         })
         .wait()
         .unwrap()
-        .get_balance();
+        .get_balance()
+        .parse::<u64>()
+        .unwrap();
 
-    println!("\nBalance of address {} = {}", TOKEN_CREATOR, balance);
+    println!("\nBalance of address {} = {}", TOKEN_CREATOR.address, balance);
     assert_eq!(
         balance, INITIAL_SUPPLY,
         "Creator did not receive initial tokens"
     );
 
-    // Populate the other accounts.
+    // Populate the other accounts in a single batch of token transfers from the creator.
+    println!("Populating {} other accounts", OTHER_ACCOUNTS.len());
+    let mut batch = evm::SubmitBatchRequest::new();
     for other_account in OTHER_ACCOUNTS.iter() {
-        // Transfer tokens from the creator to a given address.
-        println!("Populating other account {}", other_account);
+        let data = transfer_data(other_account, 1);
+        let signature = sign_transaction(
+            &TOKEN_CREATOR,
+            creator_nonce,
+            TRANSFER_GAS_LIMIT,
+            Some(&contract_addr),
+            0,
+            &data,
+        );
 
-        client
-            .transfer({
-                let mut req = evm::TransferTokenRequest::new();
-                unsafe {
-                    req.set_contract_address(CONTRACT_ADDR.as_ref().unwrap().clone());
-                }
-                req.set_from_address(TOKEN_CREATOR.to_string());
-                req.set_to_address(other_account.clone());
-                req.set_amount(1);
-                req
-            })
-            .wait()
-            .unwrap();
+        let mut transaction = evm::BatchTransaction::new();
+        transaction.set_caller_address(TOKEN_CREATOR.address.clone());
+        transaction.set_contract_address(contract_addr.clone());
+        transaction.set_data(data);
+        transaction.set_gas_limit(TRANSFER_GAS_LIMIT);
+        transaction.set_nonce(creator_nonce);
+        transaction.set_signature(signature);
+        batch.mut_transactions().push(transaction);
+        creator_nonce += 1;
+    }
+    let committed = client
+        .submit_batch(batch)
+        .wait()
+        .unwrap()
+        .get_committed();
+    assert!(committed, "Populating other accounts failed");
+
+    // Fund a sender for each benchmark thread with the tokens it will transfer.
+    let mut senders = SENDERS.lock().unwrap();
+    for _ in 0..threads {
+        let sender = Account::generate();
+        println!("Funding sender {}", sender.address);
+
+        let amount = TRANSFER_AMOUNT * runs as u64;
+        transfer(client, &TOKEN_CREATOR, creator_nonce, &sender.address, amount);
+        creator_nonce += 1;
+        senders.push(sender);
     }
 }
 
+/// Returns the proof of the account at `address`, which holds its nonce, balance and code hash.
+fn account_proof<Backend>(
+    client: &mut evm::Client<Backend>,
+    address: &str,
+) -> evm::GetProofResponse
+where
+    Backend: ekiden_rpc_client::backend::ContractClientBackend,
+{
+    client
+        .get_proof({
+            let mut req = evm::GetProofRequest::new();
+            req.set_address(address.to_string());
+            req
+        })
+        .wait()
+        .unwrap()
+}
+
+/// Returns the balance (in wei) of `address`.
+fn account_balance<Backend>(client: &mut evm::Client<Backend>, address: &str) -> u64
+where
+    Backend: ekiden_rpc_client::backend::ContractClientBackend,
+{
+    account_proof(client, address).get_balance().parse().unwrap()
+}
+
+/// Deploys a contract that self-destructs, calls it and checks that it was removed from state and
+/// that its balance went to TRANSFER_TO_ADDR.
+fn check_self_destruct<Backend>(client: &mut evm::Client<Backend>)
+where
+    Backend: ekiden_rpc_client::backend::ContractClientBackend,
+{
+    let recipient_balance = account_balance(client, TRANSFER_TO_ADDR);
+    let nonce = account_nonce(client, &TOKEN_CREATOR.address);
+
+    let code = hex::decode(SELF_DESTRUCT_CONTRACT).unwrap();
+    let signature = sign_transaction(
+        &TOKEN_CREATOR,
+        nonce,
+        CALL_GAS_LIMIT,
+        None,
+        SELF_DESTRUCT_VALUE,
+        &code,
+    );
+    let self_destruct_addr = client
+        .deploy_contract({
+            let mut req = evm::DeployContractRequest::new();
+            req.set_caller_address(TOKEN_CREATOR.address.clone());
+            req.set_code(code);
+            req.set_value(SELF_DESTRUCT_VALUE.to_string());
+            req.set_gas_limit(CALL_GAS_LIMIT);
+            req.set_nonce(nonce);
+            req.set_signature(signature);
+            req
+        })
+        .wait()
+        .unwrap()
+        .get_contract_address()
+        .to_string();
+    assert_eq!(
+        account_balance(client, &self_destruct_addr),
+        SELF_DESTRUCT_VALUE,
+        "Self-destructing contract was not funded"
+    );
+
+    let signature = sign_transaction(
+        &TOKEN_CREATOR,
+        nonce + 1,
+        CALL_GAS_LIMIT,
+        Some(&self_destruct_addr),
+        0,
+        &[],
+    );
+    let status = client
+        .call_contract({
+            let mut req = evm::CallContractRequest::new();
+            req.set_caller_address(TOKEN_CREATOR.address.clone());
+            req.set_contract_address(self_destruct_addr.clone());
+            req.set_gas_limit(CALL_GAS_LIMIT);
+            req.set_nonce(nonce + 1);
+            req.set_signature(signature);
+            req
+        })
+        .wait()
+        .unwrap()
+        .get_status();
+    assert!(status, "Self-destruct call failed");
+
+    let proof = account_proof(client, &self_destruct_addr);
+    println!(
+        "\nSelf-destructed contract {} has code hash {}",
+        self_destruct_addr,
+        proof.get_code_hash()
+    );
+    assert_eq!(
+        proof.get_code_hash(), EMPTY_CODE_HASH,
+        "Self-destructed contract was not removed"
+    );
+    assert_eq!(
+        account_balance(client, TRANSFER_TO_ADDR),
+        recipient_balance + SELF_DESTRUCT_VALUE,
+        "Balance of self-destructed contract was not transferred"
+    );
+}
+
 /// Runs the ethtoken scenario.
 fn scenario<Backend>(client: &mut evm::Client<Backend>)
 where
@@ -141,25 +496,22 @@ where
 {
     #[cfg(feature = "benchmark_transfer")]
     {
-        // Transfer tokens from the creator to a given address.
-        println!(
-            "Transferring {} tokens from {} to {}",
-            TRANSFER_AMOUNT, TOKEN_CREATOR, TRANSFER_TO_ADDR
-        );
+        // Transfer tokens from this thread's sender to a given address.
+        SENDER.with(|sender| {
+            let mut sender = sender.borrow_mut();
+            if sender.is_none() {
+                let account = SENDERS.lock().unwrap().pop().expect("no funded sender left");
+                *sender = Some((account, 0));
+            }
+            let (ref account, ref mut nonce) = *sender.as_mut().unwrap();
 
-        client
-            .transfer({
-                let mut req = evm::TransferTokenRequest::new();
-                unsafe {
-                    req.set_contract_address(CONTRACT_ADDR.as_ref().unwrap().clone());
-                }
-                req.set_from_address(TOKEN_CREATOR.to_string());
-                req.set_to_address(TRANSFER_TO_ADDR.to_string());
-                req.set_amount(TRANSFER_AMOUNT);
-                req
-            })
-            .wait()
-            .unwrap();
+            println!(
+                "Transferring {} tokens from {} to {}",
+                TRANSFER_AMOUNT, account.address, TRANSFER_TO_ADDR
+            );
+            transfer(client, account, *nonce, TRANSFER_TO_ADDR, TRANSFER_AMOUNT);
+            *nonce += 1;
+        });
     }
     #[cfg(feature = "benchmark_get_balance")]
     {
@@ -170,7 +522,7 @@ where
                 unsafe {
                     req.set_contract_address(CONTRACT_ADDR.as_ref().unwrap().clone());
                 }
-                req.set_address(TOKEN_CREATOR.to_string());
+                req.set_address(TOKEN_CREATOR.address.clone());
                 req
             })
             .wait()
@@ -179,7 +531,7 @@ where
 
         println!(
             "\nBalance of address {} = {}",
-            TOKEN_CREATOR, creator_balance
+            TOKEN_CREATOR.address, creator_balance
         );
     }
 }
@@ -196,16 +548,18 @@ where
             unsafe {
                 req.set_contract_address(CONTRACT_ADDR.as_ref().unwrap().clone());
             }
-            req.set_address(TOKEN_CREATOR.to_string());
+            req.set_address(TOKEN_CREATOR.address.clone());
             req
         })
         .wait()
         .unwrap()
-        .get_balance();
+        .get_balance()
+        .parse::<u64>()
+        .unwrap();
 
     println!(
         "\nBalance of address {} = {}",
-        TOKEN_CREATOR, creator_balance
+        TOKEN_CREATOR.address, creator_balance
     );
     #[cfg(feature = "benchmark_transfer")]
     assert_eq!(
@@ -227,7 +581,9 @@ where
         })
         .wait()
         .unwrap()
-        .get_balance();
+        .get_balance()
+        .parse::<u64>()
+        .unwrap();
 
     println!("Balance of address {} = {}", TRANSFER_TO_ADDR, dest_balance);
     #[cfg(feature = "benchmark_transfer")]
@@ -248,6 +604,7 @@ fn main() {
 fn main() {
     let mut client = contract_client!(evm);
     init(&mut client, 1, 1);
+    check_self_destruct(&mut client);
     scenario(&mut client);
     finalize(&mut client, 1, 1);
 }
@@ -0,0 +1,182 @@
+use bigint::{Address, Gas, H256, U256};
+use hexutil::to_hex;
+use protobuf::RepeatedField;
+use rlp::RlpStream;
+use sha3::{Digest, Keccak256};
+use sputnikvm::HeaderParams;
+
+use error::EvmError;
+use evm::{parse_address, parse_h256, BlockContext, TransactionResult, BLOCKHASH_WINDOW};
+use genesis::chain_config;
+use evm_api::{Block, BlockHashes, BlockNumber};
+
+use ekiden_core_common::Result;
+use ekiden_core_trusted::db::Db;
+
+const BLOCK_NUMBER_KEY: &str = "block_number";
+
+/// Hashes of the most recent blocks, oldest first, for the BLOCKHASH opcode.
+const BLOCK_HASHES_KEY: &str = "block_hashes";
+
+/// Seconds between consecutive blocks. The enclave has no trusted clock, so block timestamps
+/// advance by a fixed interval from genesis, which keeps them identical on every compute node.
+const BLOCK_INTERVAL: u64 = 15;
+
+const BLOCK_GAS_LIMIT: u64 = 8_000_000;
+
+fn block_key(number: u64) -> String {
+    format!("block:{}", number)
+}
+
+/// Returns the number of the latest block.
+pub fn latest_block_number() -> Result<u64> {
+    let number: BlockNumber = Db::instance().get(BLOCK_NUMBER_KEY)?;
+    Ok(number.get_number())
+}
+
+pub fn get_block(number: u64) -> Result<Block> {
+    // Blocks discarded by a revert remain in `Db` until they are overwritten.
+    if number > latest_block_number()? {
+        return Err(EvmError::BlockNotFound(number).into());
+    }
+    Db::instance().get(&block_key(number))
+}
+
+pub fn latest_block() -> Result<Block> {
+    get_block(latest_block_number()?)
+}
+
+/// Returns true if the transaction with the given hash was executed in block `number`, and that
+/// block is still part of the chain. Transactions and receipts discarded by a revert remain in
+/// `Db`, so lookups check this before returning them.
+pub fn transaction_in_chain(number: u64, transaction_hash: &str) -> bool {
+    match get_block(number) {
+        Ok(block) => block.get_transaction_hashes().iter().any(|h| h == transaction_hash),
+        Err(_) => false,
+    }
+}
+
+/// Returns the block following `parent`, in which the next batch of transactions will be
+/// executed.
+pub fn next_block(parent: &Block) -> Result<BlockContext> {
+    let header = HeaderParams {
+        beneficiary: parse_address(parent.get_beneficiary())?,
+        timestamp: parent.get_timestamp() + BLOCK_INTERVAL,
+        number: U256::from(parent.get_number() + 1),
+        difficulty: U256::zero(),
+        gas_limit: Gas::from(parent.get_gas_limit()),
+    };
+
+    let history: BlockHashes = Db::instance().get(BLOCK_HASHES_KEY)?;
+    let mut block_hashes = Vec::with_capacity(history.get_hashes().len());
+    for hash in history.get_hashes() {
+        block_hashes.push(parse_h256(hash)?);
+    }
+
+    Ok(BlockContext {
+        header: header,
+        fork: chain_config()?.get_fork(),
+        block_hashes: block_hashes,
+    })
+}
+
+fn block_hash(block: &Block) -> Result<H256> {
+    let mut stream = RlpStream::new_list(8);
+    stream.append(&parse_h256(block.get_parent_hash())?);
+    stream.append(&parse_address(block.get_beneficiary())?);
+    stream.append(&block.get_number());
+    stream.append(&block.get_gas_limit());
+    stream.append(&block.get_gas_used());
+    stream.append(&block.get_timestamp());
+    stream.append(&parse_h256(block.get_state_root())?);
+    stream.begin_list(block.get_transaction_hashes().len());
+    for hash in block.get_transaction_hashes() {
+        stream.append(&parse_h256(hash)?);
+    }
+
+    Ok(H256::from(Keccak256::digest(&stream.out()).as_slice()))
+}
+
+/// Stores `block` as the new latest block, following the blocks in `history`.
+fn store_block(mut block: Block, mut history: BlockHashes) -> Result<Block> {
+    let hash = block_hash(&block)?;
+    block.set_hash(to_hex(&hash));
+
+    let mut number = BlockNumber::new();
+    number.set_number(block.get_number());
+
+    history.mut_hashes().push(block.get_hash().to_string());
+    let excess = history.get_hashes().len().saturating_sub(BLOCKHASH_WINDOW);
+    if excess > 0 {
+        let recent = history.get_hashes()[excess..].to_vec();
+        history.set_hashes(RepeatedField::from_vec(recent));
+    }
+
+    let mut db = Db::instance();
+    db.set(&block_key(block.get_number()), block.clone())?;
+    db.set(BLOCK_NUMBER_KEY, number)?;
+    db.set(BLOCK_HASHES_KEY, history)?;
+    Ok(block)
+}
+
+/// Seals the block in which `results` were executed on top of `parent`, using `header` obtained
+/// from `next_block`. `state_root` is the root of the state trie after execution.
+pub fn commit_block(
+    parent: &Block,
+    header: &HeaderParams,
+    results: &[TransactionResult],
+    state_root: &H256,
+) -> Result<Block> {
+    let gas_used = results
+        .iter()
+        .fold(Gas::zero(), |total, result| total + result.gas_used);
+
+    let mut block = Block::new();
+    block.set_number(header.number.as_u64());
+    block.set_parent_hash(parent.get_hash().to_string());
+    block.set_timestamp(header.timestamp);
+    block.set_gas_limit(header.gas_limit.as_u64());
+    block.set_gas_used(gas_used.as_u64());
+    block.set_beneficiary(to_hex(&header.beneficiary));
+    block.set_transaction_hashes(RepeatedField::from_vec(
+        results.iter().map(|result| to_hex(&result.hash)).collect(),
+    ));
+    block.set_state_root(to_hex(state_root));
+
+    let history = Db::instance().get(BLOCK_HASHES_KEY)?;
+    store_block(block, history)
+}
+
+/// Stores the genesis block, discarding any existing chain. Later blocks inherit its
+/// beneficiary, and follow its timestamp at a fixed interval.
+pub fn init_genesis_block(
+    timestamp: u64,
+    beneficiary: &Address,
+    state_root: &H256,
+) -> Result<Block> {
+    let mut block = Block::new();
+    block.set_number(0);
+    block.set_parent_hash(to_hex(&H256::default()));
+    block.set_timestamp(timestamp);
+    block.set_gas_limit(BLOCK_GAS_LIMIT);
+    block.set_beneficiary(to_hex(beneficiary));
+    block.set_state_root(to_hex(state_root));
+
+    store_block(block, BlockHashes::new())
+}
+
+/// Makes block `number` the latest block again, discarding the blocks that follow it.
+pub fn rewind_chain(number: u64) -> Result<()> {
+    let first = (number + 1).saturating_sub(BLOCKHASH_WINDOW as u64);
+    let mut history = BlockHashes::new();
+    for n in first..number + 1 {
+        history.mut_hashes().push(get_block(n)?.take_hash());
+    }
+
+    let mut block_number = BlockNumber::new();
+    block_number.set_number(number);
+
+    let mut db = Db::instance();
+    db.set(BLOCK_NUMBER_KEY, block_number)?;
+    db.set(BLOCK_HASHES_KEY, history)
+}
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bigint::{H256, M256, U256};
+use hexutil::to_hex;
+
+use error::{EvmError, Result};
+use evm::{parse_address, parse_hex};
+use evm_api::{AccountState, DumpAccount, DumpStateResponse};
+use genesis::parse_quantity;
+use state::{account_count, account_range, account_storage, apply_changes, code_hash, decode_u256,
+            encode_u256, get_account, get_code, move_code, nonce_u64, state_root, storage_root,
+            StateChanges};
+
+// Accounts are converted to and from the JSON shape of `geth dump`, keyed by address: balance in
+// decimal, nonce, storage root, code hash, code in hex and a map of non-zero storage slots, whose
+// keys and values are 32-byte hex words. Large states are moved in chunks of accounts, following
+// the order of the account index.
+
+/// Number of accounts dumped per chunk when the request doesn't set a limit.
+const DEFAULT_DUMP_LIMIT: u64 = 256;
+
+/// Parses a storage key or value, given as hex with or without a "0x" prefix.
+fn parse_word(value: &str) -> Result<U256> {
+    let bytes = parse_hex(value)?;
+    if bytes.len() > 32 {
+        return Err(EvmError::InvalidNumber(value.to_string()));
+    }
+    Ok(U256::from(&bytes[..]))
+}
+
+fn word_hex(value: &U256) -> String {
+    let mut word = [0u8; 32];
+    value.to_big_endian(&mut word);
+    to_hex(&word)
+}
+
+fn dump_account(account: &AccountState, storage: BTreeMap<U256, M256>) -> Result<DumpAccount> {
+    let mut dumped = DumpAccount::new();
+    dumped.set_balance(format!("{}", decode_u256(account.get_balance())?));
+    dumped.set_nonce(nonce_u64(&decode_u256(account.get_nonce())?)?);
+    dumped.set_root(to_hex(&storage_root(account)?));
+
+    let hash = code_hash(account)?;
+    dumped.set_code_hash(to_hex(&hash));
+    dumped.set_code(to_hex(&get_code(&hash)?));
+
+    for (key, value) in storage {
+        let value: U256 = value.into();
+        if !value.is_zero() {
+            dumped.mut_storage().insert(word_hex(&key), word_hex(&value));
+        }
+    }
+    Ok(dumped)
+}
+
+/// Dumps up to `limit` accounts, starting at position `start` of the account index. `next` is set
+/// to the position of the following chunk, or to zero once the last account has been dumped.
+pub fn dump_accounts(start: u64, limit: u64) -> Result<DumpStateResponse> {
+    let limit = if limit == 0 { DEFAULT_DUMP_LIMIT } else { limit };
+    let count = account_count();
+
+    let mut response = DumpStateResponse::new();
+    response.set_root(to_hex(&state_root()?));
+
+    let end = count.min(start.saturating_add(limit));
+    for address in account_range(start, end)? {
+        let account = match get_account(&address) {
+            Some(account) => account,
+            None => continue,
+        };
+        let dumped = dump_account(&account, account_storage(&address)?)?;
+        response.mut_accounts().insert(to_hex(&address), dumped);
+    }
+
+    if end < count {
+        response.set_next(end);
+    }
+    Ok(response)
+}
+
+/// Writes `accounts`, given in `geth dump` form, to the state and returns the new state root.
+/// Existing accounts are overwritten, but storage slots missing from the dump are left as they
+/// are. The code hash and storage root in the dump are ignored and recomputed.
+pub fn load_accounts(accounts: &HashMap<String, DumpAccount>) -> Result<H256> {
+    let mut changes = StateChanges::default();
+    for (address, dumped) in accounts {
+        let address = parse_address(address)?;
+
+        let mut account = AccountState::new();
+        account.set_nonce(encode_u256(&U256::from(dumped.get_nonce())));
+        account.set_address(address.to_vec());
+        account.set_balance(encode_u256(&parse_quantity(dumped.get_balance())?));
+        if !dumped.get_code().is_empty() {
+            account.set_code(parse_hex(dumped.get_code())?);
+        }
+        move_code(&mut account, &mut changes.code);
+        changes.accounts.insert(address, account);
+
+        let mut storage = BTreeMap::new();
+        for (key, value) in dumped.get_storage() {
+            storage.insert(parse_word(key)?, M256(parse_word(value)?));
+        }
+        changes.storage.insert(address, storage);
+    }
+
+    apply_changes(changes)
+}
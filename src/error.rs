@@ -0,0 +1,121 @@
+use std::fmt;
+use std::result;
+
+use bigint::{Address, H256};
+use sputnikvm::CommitError;
+
+use ekiden_core_common;
+
+/// Errors raised while bridging between the EVM and Ekiden state.
+#[derive(Debug)]
+pub enum EvmError {
+    /// A string is not a valid hex-encoded address.
+    InvalidAddress(String),
+    /// A string is not a valid decimal number.
+    InvalidNumber(String),
+    /// A string is not valid hex.
+    InvalidHex(String),
+    /// A value read from state doesn't have the expected binary encoding.
+    InvalidEncoding(String),
+    /// The VM rejected a commitment made in response to one of its requirements.
+    Commit(CommitError),
+    /// The VM asked about an account that is missing from state.
+    MissingAccount(Address),
+    /// An account can't pay for a transaction.
+    InsufficientBalance(Address),
+    /// The transactions' gas limits add up to more than the block gas limit.
+    BlockGasLimitExceeded,
+    /// A transaction's gas limit doesn't cover its intrinsic gas.
+    IntrinsicGasTooLow,
+    /// A contract returned data that doesn't have the length its ABI specifies.
+    InvalidReturnData(Address),
+    /// A raw transaction can't be decoded.
+    InvalidTransaction(String),
+    /// A signature is malformed, or isn't canonical.
+    InvalidSignature,
+    /// A request is signed by another key than the one of the address it acts for.
+    UnauthorizedSender(Address),
+    /// A transaction is signed for another chain, or isn't replay-protected by EIP-155.
+    InvalidChainId(Option<u64>),
+    /// A transaction's nonce doesn't match the nonce of its sender.
+    InvalidNonce(Address),
+    /// No transaction with this hash was executed in the chain.
+    TransactionNotFound(H256),
+    /// The chain has no block with this number.
+    BlockNotFound(u64),
+    /// No snapshot with this id was taken, or it was discarded by a revert.
+    UnknownSnapshot(u64),
+    /// A request rewrites state outside of transactions, which only chains created in
+    /// development mode allow.
+    DevModeOnly,
+    /// The stored state uses a layout this version can't read, and must be migrated.
+    UnsupportedStateVersion(u32),
+    /// Reading or writing state in the enclave database failed.
+    Db(ekiden_core_common::Error),
+}
+
+pub type Result<T> = result::Result<T, EvmError>;
+
+impl fmt::Display for EvmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EvmError::InvalidAddress(ref value) => write!(f, "invalid address: {}", value),
+            EvmError::InvalidNumber(ref value) => write!(f, "invalid number: {}", value),
+            EvmError::InvalidHex(ref value) => write!(f, "invalid hex: {}", value),
+            EvmError::InvalidEncoding(ref reason) => write!(f, "invalid encoding: {}", reason),
+            EvmError::Commit(ref error) => write!(f, "VM rejected commitment: {:?}", error),
+            EvmError::MissingAccount(ref address) => {
+                write!(f, "account missing from state: {}", address.hex())
+            }
+            EvmError::InsufficientBalance(ref address) => {
+                write!(f, "insufficient balance: {}", address.hex())
+            }
+            EvmError::BlockGasLimitExceeded => write!(f, "block gas limit exceeded"),
+            EvmError::IntrinsicGasTooLow => write!(f, "intrinsic gas too low"),
+            EvmError::InvalidReturnData(ref address) => {
+                write!(f, "invalid return data from {}", address.hex())
+            }
+            EvmError::InvalidTransaction(ref reason) => {
+                write!(f, "invalid transaction: {}", reason)
+            }
+            EvmError::InvalidSignature => write!(f, "invalid signature"),
+            EvmError::UnauthorizedSender(ref address) => {
+                write!(f, "request is not signed by {}", address.hex())
+            }
+            EvmError::InvalidChainId(Some(chain_id)) => write!(f, "invalid chain id: {}", chain_id),
+            EvmError::InvalidChainId(None) => write!(f, "transaction is not replay-protected"),
+            EvmError::InvalidNonce(ref address) => write!(f, "invalid nonce: {}", address.hex()),
+            EvmError::TransactionNotFound(ref hash) => {
+                write!(f, "transaction not found: {}", hash.hex())
+            }
+            EvmError::BlockNotFound(number) => write!(f, "block not found: {}", number),
+            EvmError::UnknownSnapshot(id) => write!(f, "unknown snapshot: {}", id),
+            EvmError::DevModeOnly => write!(f, "only allowed in development mode"),
+            EvmError::UnsupportedStateVersion(version) => {
+                write!(f, "unsupported state version: {}", version)
+            }
+            EvmError::Db(ref error) => write!(f, "database error: {}", error),
+        }
+    }
+}
+
+impl From<CommitError> for EvmError {
+    fn from(error: CommitError) -> Self {
+        EvmError::Commit(error)
+    }
+}
+
+impl From<ekiden_core_common::Error> for EvmError {
+    fn from(error: ekiden_core_common::Error) -> Self {
+        EvmError::Db(error)
+    }
+}
+
+impl From<EvmError> for ekiden_core_common::Error {
+    fn from(error: EvmError) -> Self {
+        match error {
+            EvmError::Db(error) => error,
+            error => ekiden_core_common::Error::new(format!("{}", error)),
+        }
+    }
+}
@@ -1,142 +1,196 @@
 extern crate alloc;
 extern crate bigint;
 extern crate hexutil;
+extern crate rlp;
 extern crate sha3;
 extern crate sputnikvm;
+extern crate sputnikvm_network_foundation;
 
-use std::collections::HashMap;
-
-use bigint::{Address, Gas, H256, M256, Sign, U256};
+use bigint::{Address, Gas, H256, U256};
 
-use evm_api::{AccountState, EthState};
-use hexutil::{read_hex, to_hex};
+use evm_api::{AccountState, Fork};
+use error::{EvmError, Result};
+use hexutil::read_hex;
+use rlp::RlpStream;
+use sha3::{Digest, Keccak256};
 
-use std::str::FromStr;
-use sputnikvm::{AccountChange, AccountCommitment, HeaderParams, MainnetEIP160Patch, RequireError,
-                SeqTransactionVM, Storage, ValidTransaction, VM};
+use sputnikvm::{AccountChange, AccountCommitment, AccountPatch, HeaderParams, Log, Patch,
+                RequireError, SeqTransactionVM, TransactionAction, VMStatus, ValidTransaction, VM};
+use sputnikvm_network_foundation::{ByzantiumPatch, EIP150Patch, FrontierPatch, HomesteadPatch,
+                                   SpuriousDragonPatch};
+use state::{code_hash, decode_u256, empty_code_hash, encode_u256, get_account, get_code,
+            get_storage, StateChanges};
+use transaction::HashedTransaction;
 
+use std::collections::HashMap;
 use std::rc::Rc;
 
 // Internal methods. These methods handle the EVM and provide a bridge between Ethereum state
 // and Ekiden state.
 
-fn handle_fire(vm: &mut SeqTransactionVM<MainnetEIP160Patch>, state: &EthState) {
+/// Parses a hex-encoded address.
+pub fn parse_address(value: &str) -> Result<Address> {
+    value
+        .parse()
+        .map_err(|_| EvmError::InvalidAddress(value.to_string()))
+}
+
+/// Parses a decimal number.
+pub fn parse_u256(value: &str) -> Result<U256> {
+    U256::from_dec_str(value).map_err(|_| EvmError::InvalidNumber(value.to_string()))
+}
+
+/// Parses a hex-encoded 256-bit hash.
+pub fn parse_h256(value: &str) -> Result<H256> {
+    value
+        .parse()
+        .map_err(|_| EvmError::InvalidHex(value.to_string()))
+}
+
+/// Parses a hex string into bytes.
+pub fn parse_hex(value: &str) -> Result<Vec<u8>> {
+    read_hex(value).map_err(|_| EvmError::InvalidHex(value.to_string()))
+}
+
+/// Number of most recent blocks whose hashes are available to the BLOCKHASH opcode.
+pub const BLOCKHASH_WINDOW: usize = 256;
+
+/// The block in which transactions are executed.
+pub struct BlockContext {
+    pub header: HeaderParams,
+    /// Hard fork whose rules apply to the block.
+    pub fork: Fork,
+    /// Hashes of the blocks preceding this one, oldest first. Only the last `BLOCKHASH_WINDOW`
+    /// are ever used.
+    pub block_hashes: Vec<H256>,
+}
+
+impl BlockContext {
+    /// Returns the hash of block `number` as seen by the BLOCKHASH opcode. As per the Yellow
+    /// Paper, this is zero unless `number` is one of the 256 blocks preceding the current one.
+    fn blockhash(&self, number: U256) -> H256 {
+        let current = self.header.number;
+        if number >= current || current - number > U256::from(BLOCKHASH_WINDOW) {
+            return H256::default();
+        }
+
+        let depth = (current - number).as_u64() as usize;
+        if depth > self.block_hashes.len() {
+            return H256::default();
+        }
+        self.block_hashes[self.block_hashes.len() - depth]
+    }
+}
+
+/// Runs the VM, loading accounts and storage slots from the enclave state as it requests them.
+fn handle_fire<P: Patch>(vm: &mut SeqTransactionVM<P>, block: &BlockContext) -> Result<()> {
     loop {
         match vm.fire() {
             Ok(()) => break,
             Err(RequireError::Account(address)) => {
-                let addr_str = address.hex();
-                let commit = match state.accounts.get(&addr_str) {
-                    Some(b) => {
-                        let result = AccountCommitment::Full {
-                            nonce: U256::from_dec_str(b.get_nonce()).unwrap(),
-                            address: address,
-                            balance: U256::from_dec_str(b.get_balance()).unwrap(),
-                            code: Rc::new(read_hex(b.get_code()).unwrap()),
-                        };
-                        result
-                    }
+                let commit = match get_account(&address) {
+                    Some(b) => AccountCommitment::Full {
+                        nonce: decode_u256(b.get_nonce())?,
+                        address: address,
+                        balance: decode_u256(b.get_balance())?,
+                        code: Rc::new(get_code(&code_hash(&b)?)?),
+                    },
                     None => AccountCommitment::Nonexist(address),
                 };
-                vm.commit_account(commit).unwrap();
+                vm.commit_account(commit)?;
             }
             Err(RequireError::AccountStorage(address, index)) => {
-                let addr_str = address.hex();
-                let index_str = format!("{}", index);
-
-                let value = match state
-                    .accounts
-                    .get(&addr_str)
-                    .unwrap()
-                    .storage
-                    .get(&index_str)
-                {
-                    Some(b) => M256(U256::from_dec_str(b).unwrap()),
-                    None => M256::zero(),
-                };
+                if get_account(&address).is_none() {
+                    return Err(EvmError::MissingAccount(address));
+                }
+                let value = get_storage(&address, &index)?;
 
                 vm.commit_account(AccountCommitment::Storage {
                     address: address,
                     index: index,
                     value: value,
-                }).unwrap();
+                })?;
             }
             Err(RequireError::AccountCode(address)) => {
-                vm.commit_account(AccountCommitment::Nonexist(address))
-                    .unwrap();
+                let commit = match get_account(&address) {
+                    Some(b) => AccountCommitment::Code {
+                        address: address,
+                        code: Rc::new(get_code(&code_hash(&b)?)?),
+                    },
+                    None => AccountCommitment::Nonexist(address),
+                };
+                vm.commit_account(commit)?;
             }
             Err(RequireError::Blockhash(number)) => {
-                vm.commit_blockhash(number, H256::default()).unwrap();
+                vm.commit_blockhash(number, block.blockhash(number))?;
             }
         }
     }
+
+    Ok(())
 }
 
 fn create_account_state(
     nonce: U256,
     address: Address,
     balance: U256,
-    storage: &Storage,
-    code: &Rc<Vec<u8>>,
-) -> (String, AccountState) {
-    let mut storage_map: HashMap<String, String> = HashMap::new();
-    let vm_storage_as_map: alloc::BTreeMap<U256, M256> = storage.clone().into();
-    for (key, val) in vm_storage_as_map.iter() {
-        let val_as_u256: U256 = val.clone().into();
-        storage_map.insert(format!("{}", key), format!("{}", val_as_u256));
-    }
-
-    let address_str = address.hex();
+    code_hash: H256,
+) -> AccountState {
     let mut account_state = AccountState::new();
 
-    account_state.set_nonce(format!("{}", nonce));
-    account_state.set_address(address_str.clone());
-    account_state.set_balance(format!("{}", balance));
-    account_state.set_storage(storage_map);
-    account_state.set_code(to_hex(code));
+    account_state.set_nonce(encode_u256(&nonce));
+    account_state.set_address(address.to_vec());
+    account_state.set_balance(encode_u256(&balance));
+    account_state.set_code_hash(code_hash.to_vec());
 
-    (address_str, account_state)
+    account_state
 }
 
-fn update_account_balance(
-    address_str: &String,
-    amount: U256,
-    sign: Sign,
-    state: &EthState,
-) -> AccountState {
-    match state.accounts.get(address_str) {
-        Some(b) => {
+fn hash_code(code: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(code).as_slice())
+}
+
+fn increase_account_balance(address: Address, amount: U256) -> Result<AccountState> {
+    match get_account(&address) {
+        Some(mut updated_account) => {
             // Found account. Update balance.
-            let mut updated_account = b.clone();
-            let prev_balance: U256 = U256::from_str(b.get_balance()).unwrap();
-            let new_balance = match sign {
-                Sign::Plus => prev_balance + amount,
-                Sign::Minus => prev_balance - amount,
-                _ => panic!(),
-            };
-            updated_account.set_balance(format!("{}", new_balance));
-            updated_account
+            let prev_balance = decode_u256(updated_account.get_balance())?;
+            updated_account.set_balance(encode_u256(&(prev_balance + amount)));
+            Ok(updated_account)
         }
         None => {
             // Account doesn't exist; create it.
-            assert_eq!(
-                sign,
-                Sign::Plus,
-                "Can't decrease balance of nonexistent account"
-            );
             let mut account_state = AccountState::new();
-            account_state.set_nonce("0".to_string());
-            account_state.set_address(address_str.clone());
-            account_state.set_balance(format!("{}", amount));
-            account_state
+            account_state.set_nonce(encode_u256(&U256::zero()));
+            account_state.set_address(address.to_vec());
+            account_state.set_balance(encode_u256(&amount));
+            Ok(account_state)
         }
     }
 }
 
-fn update_state_from_vm(vm: &SeqTransactionVM<MainnetEIP160Patch>, _state: &EthState) -> EthState {
-    let mut state = _state.clone();
+/// Checks whether an account is empty as defined by EIP-161: it has no code, and its nonce and
+/// balance are zero.
+fn is_empty_account(account: &AccountState) -> Result<bool> {
+    Ok(decode_u256(account.get_nonce())?.is_zero()
+        && decode_u256(account.get_balance())?.is_zero()
+        && code_hash(account)? == empty_code_hash())
+}
+
+/// Collects the changes the VM made to accounts and storage. The enclave state itself is left
+/// untouched.
+fn update_state_from_vm<P: Patch>(vm: &SeqTransactionVM<P>) -> Result<StateChanges> {
+    state_changes::<P, _>(vm.accounts())
+}
+
+/// Converts the account changes reported by the VM into changes to the enclave state.
+fn state_changes<'a, P: Patch, I>(accounts: I) -> Result<StateChanges>
+where
+    I: IntoIterator<Item = &'a AccountChange>,
+{
+    let mut changes = StateChanges::default();
 
-    for account in vm.accounts() {
+    for account in accounts {
         match account {
             &AccountChange::Create {
                 nonce,
@@ -145,9 +199,13 @@ fn update_state_from_vm(vm: &SeqTransactionVM<MainnetEIP160Patch>, _state: &EthS
                 ref storage,
                 ref code,
             } => {
-                let (addr_str, account_state) =
-                    create_account_state(nonce, address, balance, storage, code);
-                state.accounts.insert(addr_str, account_state);
+                let code_hash = hash_code(code);
+                let account_state = create_account_state(nonce, address, balance, code_hash);
+                changes.accounts.insert(address, account_state);
+                changes.storage.insert(address, storage.clone().into());
+                if !code.is_empty() {
+                    changes.code.insert(code_hash, code.to_vec());
+                }
             }
             &AccountChange::Full {
                 nonce,
@@ -156,55 +214,159 @@ fn update_state_from_vm(vm: &SeqTransactionVM<MainnetEIP160Patch>, _state: &EthS
                 ref changing_storage,
                 ref code,
             } => {
-                let (addr_str, mut account_state) =
-                    create_account_state(nonce, address, balance, changing_storage, code);
-                let prev_storage = &_state.accounts.get(&addr_str).unwrap().storage;
-
-                // This type of change registers a *diff* of the storage, so place previous values
-                // in the new map.
-                for (key, value) in prev_storage.iter() {
-                    if !account_state.storage.contains_key(key) {
-                        account_state
-                            .mut_storage()
-                            .insert(key.clone(), value.clone());
-                    }
-                }
-
-                state.mut_accounts().insert(addr_str, account_state);
+                // This type of change registers a *diff* of the storage, so only the slots it
+                // contains are written back. The code can't change, and is already stored.
+                let account_state =
+                    create_account_state(nonce, address, balance, hash_code(code));
+                changes.accounts.insert(address, account_state);
+                changes
+                    .storage
+                    .insert(address, changing_storage.clone().into());
             }
             &AccountChange::IncreaseBalance(address, amount) => {
-                let address_str = address.hex();
-                let new_account = update_account_balance(&address_str, amount, Sign::Plus, &state);
-                state.accounts.insert(address_str, new_account);
-            }
-            &AccountChange::DecreaseBalance(address, amount) => {
-                let address_str = address.hex();
-                let new_account = update_account_balance(&address_str, amount, Sign::Minus, &state);
-                state.accounts.insert(address_str, new_account);
+                // From EIP-161 on, touched accounts that end up empty are removed from state.
+                // sputnikvm already reports `Full` and `Create` changes that leave an account
+                // empty as `Nonexist`, but not balance increases, which touch an account even
+                // when the amount is zero.
+                let new_account = increase_account_balance(address, amount)?;
+                if !P::Account::empty_considered_exists() && is_empty_account(&new_account)? {
+                    changes.removed.insert(address);
+                } else {
+                    changes.accounts.insert(address, new_account);
+                }
             }
             &AccountChange::Nonexist(address) => {
-                panic!("Unexpected nonexistent address: {:?}", address)
+                // The account doesn't exist after the transaction: it self-destructed, and its
+                // balance was moved to the beneficiary, whose own change is reported separately,
+                // or it was left empty and cleared under EIP-161. sputnikvm also reports accounts
+                // it only looked up and found missing, which removing leaves unchanged.
+                changes.removed.insert(address);
             }
         }
     }
 
-    state
+    Ok(changes)
 }
 
-pub fn fire_transactions_and_update_state(
-    transactions: &[ValidTransaction],
-    state: &EthState,
-) -> (EthState, Vec<u8>) {
-    let block_header = HeaderParams {
-        beneficiary: Address::default(),
-        timestamp: 0,
-        number: U256::zero(),
-        difficulty: U256::zero(),
-        gas_limit: Gas::zero(),
+/// Returns the current nonce of `address`, or zero if the account doesn't exist yet.
+pub fn account_nonce(address: &Address) -> Result<U256> {
+    match get_account(address) {
+        Some(account) => decode_u256(account.get_nonce()),
+        None => Ok(U256::zero()),
+    }
+}
+
+/// Returns the native balance of `address`, or zero if the account doesn't exist yet.
+pub fn account_balance(address: &Address) -> Result<U256> {
+    match get_account(address) {
+        Some(account) => decode_u256(account.get_balance()),
+        None => Ok(U256::zero()),
+    }
+}
+
+/// Checks that `transactions` fit in the block's gas limit and that each sender can pay for the
+/// gas it buys and the value it sends, which sputnikvm assumes of a `ValidTransaction`. The VM
+/// then debits `gas_limit * gas_price` up front, refunds unused gas to the sender and credits
+/// the gas used to the block beneficiary. A sender's balance before the block must cover all of
+/// its transactions together, since any of them may spend it all.
+fn validate_transactions(transactions: &[HashedTransaction], block: &BlockContext) -> Result<()> {
+    let block_gas_limit: U256 = block.header.gas_limit.into();
+    let mut total_gas_limit = U256::zero();
+    let mut total_costs: HashMap<Address, U256> = HashMap::new();
+    for t in transactions.iter().map(|t| &t.transaction) {
+        let gas_limit: U256 = t.gas_limit.into();
+        let gas_price: U256 = t.gas_price.into();
+
+        let (total, overflow) = total_gas_limit.overflowing_add(gas_limit);
+        if overflow || total > block_gas_limit {
+            return Err(EvmError::BlockGasLimitExceeded);
+        }
+        total_gas_limit = total;
+
+        if let Some(caller) = t.caller {
+            let previous_cost = total_costs.get(&caller).cloned().unwrap_or_default();
+            let (gas_cost, mul_overflow) = gas_limit.overflowing_mul(gas_price);
+            let (cost, value_overflow) = gas_cost.overflowing_add(t.value);
+            let (total_cost, total_overflow) = previous_cost.overflowing_add(cost);
+            if mul_overflow || value_overflow || total_overflow
+                || account_balance(&caller)? < total_cost
+            {
+                return Err(EvmError::InsufficientBalance(caller));
+            }
+            total_costs.insert(caller, total_cost);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that `transaction`'s gas limit covers its intrinsic gas. sputnikvm only checks this for
+/// untrusted transactions, and subtracts the intrinsic gas from the gas limit of a
+/// `ValidTransaction` without checking for underflow.
+fn check_intrinsic_gas<P: Patch>(transaction: &ValidTransaction) -> Result<()> {
+    if transaction.gas_limit < transaction.intrinsic_gas::<P>() {
+        return Err(EvmError::IntrinsicGasTooLow);
+    }
+    Ok(())
+}
+
+/// Computes the address of a contract created by `sender` with the given nonce, i.e. the last 20
+/// bytes of keccak256(rlp([sender, nonce])).
+pub fn contract_address(sender: &Address, nonce: &U256) -> Address {
+    let mut stream = RlpStream::new_list(2);
+    stream.append(sender);
+    stream.append(nonce);
+    Address::from(&Keccak256::digest(&stream.out())[12..])
+}
+
+/// Returns the address of the contract created by `transaction`, provided the VM actually
+/// created it (a failed constructor leaves no account behind).
+fn created_address<P: Patch>(
+    vm: &SeqTransactionVM<P>,
+    transaction: &ValidTransaction,
+) -> Option<Address> {
+    let caller = match (&transaction.action, transaction.caller) {
+        (&TransactionAction::Create, Some(caller)) => caller,
+        _ => return None,
     };
+    let expected = contract_address(&caller, &transaction.nonce);
+
+    vm.accounts()
+        .filter_map(|account| match account {
+            &AccountChange::Create { address, .. } if address == expected => Some(address),
+            _ => None,
+        })
+        .next()
+}
+
+/// Outcome of a single transaction passed to `fire_transactions_and_update_state`.
+pub struct TransactionResult {
+    pub hash: H256,
+    /// Number of the block the transaction was executed in.
+    pub block_number: U256,
+    /// Return data (for a call) or deployed code (for a create).
+    pub output: Vec<u8>,
+    pub gas_used: Gas,
+    /// True if the transaction completed without an exception.
+    pub status: bool,
+    /// Address of the contract created by the transaction, if any.
+    pub contract_address: Option<Address>,
+    pub logs: Vec<Log>,
+}
 
-    let mut last_vm: Option<SeqTransactionVM<MainnetEIP160Patch>> = None;
+fn fire_transactions<P: Patch>(
+    transactions: &[HashedTransaction],
+    block: &BlockContext,
+) -> Result<(StateChanges, Vec<TransactionResult>)> {
     for t in transactions.iter() {
+        check_intrinsic_gas::<P>(&t.transaction)?;
+    }
+    let block_header = &block.header;
+
+    let mut results = Vec::with_capacity(transactions.len());
+    let mut last_vm: Option<SeqTransactionVM<P>> = None;
+    for hashed in transactions.iter() {
+        let t = &hashed.transaction;
         let mut vm = if last_vm.is_none() {
             SeqTransactionVM::new(t.clone(), block_header.clone())
         } else {
@@ -215,12 +377,115 @@ pub fn fire_transactions_and_update_state(
             )
         };
 
-        handle_fire(&mut vm, state);
+        handle_fire(&mut vm, block)?;
+        results.push(TransactionResult {
+            hash: hashed.hash,
+            block_number: block_header.number,
+            output: vm.out().to_vec(),
+            gas_used: vm.used_gas(),
+            status: match vm.status() {
+                VMStatus::ExitedOk => true,
+                _ => false,
+            },
+            contract_address: created_address(&vm, t),
+            logs: vm.logs().to_vec(),
+        });
         last_vm = Some(vm);
     }
 
-    let vm_result = last_vm.as_ref().unwrap().out();
+    let changes = update_state_from_vm(last_vm.as_ref().unwrap())?;
+    Ok((changes, results))
+}
+
+/// Executes `transactions` in order under the rules of the block's fork, and returns the
+/// changes to apply to the state along with the outcome of each transaction.
+pub fn fire_transactions_and_update_state(
+    transactions: &[HashedTransaction],
+    block: &BlockContext,
+) -> Result<(StateChanges, Vec<TransactionResult>)> {
+    validate_transactions(transactions, block)?;
+
+    match block.fork {
+        Fork::FRONTIER => fire_transactions::<FrontierPatch>(transactions, block),
+        Fork::HOMESTEAD => fire_transactions::<HomesteadPatch>(transactions, block),
+        Fork::EIP150 => fire_transactions::<EIP150Patch>(transactions, block),
+        Fork::EIP160 => fire_transactions::<SpuriousDragonPatch>(transactions, block),
+        Fork::BYZANTIUM => fire_transactions::<ByzantiumPatch>(transactions, block),
+    }
+}
+
+fn static_call<P: Patch>(
+    transaction: ValidTransaction,
+    block: &BlockContext,
+) -> Result<(Vec<u8>, Gas)> {
+    check_intrinsic_gas::<P>(&transaction)?;
+    let mut vm: SeqTransactionVM<P> = SeqTransactionVM::new(transaction, block.header.clone());
+    handle_fire(&mut vm, block)?;
+    Ok((vm.out().to_vec(), vm.used_gas()))
+}
+
+/// Executes a single transaction against the current state and returns its output and the gas
+/// it used. Account changes made by the transaction are never applied, so the state can't be
+/// modified.
+pub fn fire_static_call(
+    transaction: ValidTransaction,
+    block: &BlockContext,
+) -> Result<(Vec<u8>, Gas)> {
+    match block.fork {
+        Fork::FRONTIER => static_call::<FrontierPatch>(transaction, block),
+        Fork::HOMESTEAD => static_call::<HomesteadPatch>(transaction, block),
+        Fork::EIP150 => static_call::<EIP150Patch>(transaction, block),
+        Fork::EIP160 => static_call::<SpuriousDragonPatch>(transaction, block),
+        Fork::BYZANTIUM => static_call::<ByzantiumPatch>(transaction, block),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bigint::{Address, M256, U256};
+    use sputnikvm::AccountChange;
+    use sputnikvm_network_foundation::SpuriousDragonPatch;
+
+    use state::{account_addresses, account_storage, apply_changes, empty_code_hash, get_account,
+                get_storage, StateChanges};
+
+    use super::{create_account_state, state_changes};
 
-    let new_state = update_state_from_vm(&last_vm.as_ref().unwrap(), state);
-    (new_state, vm_result.to_vec())
+    #[test]
+    fn test_remove_nonexistent_and_emptied_accounts() {
+        let destructed = Address::from_str("0x1000000000000000000000000000000000000001").unwrap();
+        let emptied = Address::from_str("0x1000000000000000000000000000000000000002").unwrap();
+        let slot = U256::from(1);
+
+        let mut changes = StateChanges::default();
+        changes.accounts.insert(
+            destructed,
+            create_account_state(U256::one(), destructed, U256::from(5), empty_code_hash()),
+        );
+        changes.accounts.insert(
+            emptied,
+            create_account_state(U256::zero(), emptied, U256::zero(), empty_code_hash()),
+        );
+        changes
+            .storage
+            .insert(destructed, vec![(slot, M256::from(7))].into_iter().collect());
+        apply_changes(changes).unwrap();
+        assert_eq!(account_addresses().unwrap().len(), 2);
+
+        // Under EIP-161, touching an empty account with a zero balance increase removes it.
+        let changes = state_changes::<SpuriousDragonPatch, _>(&[
+            AccountChange::Nonexist(destructed),
+            AccountChange::IncreaseBalance(emptied, U256::zero()),
+        ]).unwrap();
+        apply_changes(changes).unwrap();
+
+        for address in &[destructed, emptied] {
+            assert!(get_account(address).is_none());
+            assert!(account_storage(address).unwrap().is_empty());
+        }
+        assert_eq!(get_storage(&destructed, &slot).unwrap(), M256::zero());
+        assert!(account_addresses().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,76 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use bigint::{M256, U256};
+
+use error::{EvmError, Result as EvmResult};
+use evm::{parse_address, parse_hex, parse_u256};
+use evm_api::{AccountState, ChainConfig, GenesisAccount};
+use state::{encode_u256, move_code, StateChanges};
+
+use ekiden_core_common::Result;
+use ekiden_core_trusted::db::Db;
+
+const CHAIN_CONFIG_KEY: &str = "chain_config";
+
+/// Returns the chain configuration chosen at genesis.
+pub fn chain_config() -> Result<ChainConfig> {
+    Db::instance().get(CHAIN_CONFIG_KEY)
+}
+
+pub fn store_chain_config(config: ChainConfig) -> Result<()> {
+    Db::instance().set(CHAIN_CONFIG_KEY, config)
+}
+
+/// Checks that the chain was created in development mode, or that there is no chain yet. Requests
+/// that rewrite state outside of transactions are only allowed then.
+pub fn check_dev_mode() -> EvmResult<()> {
+    match chain_config() {
+        Ok(ref config) if !config.get_dev_mode() => Err(EvmError::DevModeOnly),
+        _ => Ok(()),
+    }
+}
+
+/// Parses a quantity from a genesis spec, given either in hex with a "0x" prefix, as geth writes
+/// them, or in decimal. An empty string means zero.
+pub fn parse_quantity(value: &str) -> EvmResult<U256> {
+    if value.starts_with("0x") {
+        let digits = &value[2..];
+        if digits.is_empty() {
+            return Ok(U256::zero());
+        }
+        if digits.len() > 64 {
+            return Err(EvmError::InvalidNumber(value.to_string()));
+        }
+        U256::from_str(digits).map_err(|_| EvmError::InvalidNumber(value.to_string()))
+    } else if value.is_empty() {
+        Ok(U256::zero())
+    } else {
+        parse_u256(value)
+    }
+}
+
+/// Builds the state changes that create the accounts allocated at genesis, keyed by address.
+pub fn genesis_alloc(alloc: &HashMap<String, GenesisAccount>) -> EvmResult<StateChanges> {
+    let mut changes = StateChanges::default();
+    for (address, spec) in alloc {
+        let address = parse_address(address)?;
+
+        let mut account = AccountState::new();
+        account.set_nonce(encode_u256(&parse_quantity(spec.get_nonce())?));
+        account.set_address(address.to_vec());
+        account.set_balance(encode_u256(&parse_quantity(spec.get_balance())?));
+        if !spec.get_code().is_empty() {
+            account.set_code(parse_hex(spec.get_code())?);
+        }
+        move_code(&mut account, &mut changes.code);
+        changes.accounts.insert(address, account);
+
+        let mut storage = BTreeMap::new();
+        for (key, value) in spec.get_storage() {
+            storage.insert(parse_quantity(key)?, M256(parse_quantity(value)?));
+        }
+        changes.storage.insert(address, storage);
+    }
+    Ok(changes)
+}
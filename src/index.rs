@@ -0,0 +1,137 @@
+use hexutil::to_hex;
+
+use error::Result;
+use evm_api::{IndexEntry, IndexLength, IndexPosition};
+
+use ekiden_core_trusted::db::Db;
+
+/// A set of byte strings stored with one `Db` entry per member, so that inserting or removing a
+/// member writes a constant number of entries however large the set grows. Members are numbered
+/// from zero in insertion order, and removing one moves the last member into its position, which
+/// keeps the numbering dense and identical on every compute node.
+pub struct Index {
+    prefix: String,
+}
+
+impl Index {
+    /// Opens the index whose entries are stored under `prefix`.
+    pub fn new(prefix: String) -> Self {
+        Index { prefix: prefix }
+    }
+
+    fn length_key(&self) -> String {
+        format!("{}:length", self.prefix)
+    }
+
+    fn entry_key(&self, position: u64) -> String {
+        format!("{}:{}", self.prefix, position)
+    }
+
+    fn position_key(&self, member: &[u8]) -> String {
+        format!("{}:position:{}", self.prefix, to_hex(member))
+    }
+
+    /// Returns the number of members.
+    pub fn len(&self) -> u64 {
+        Db::instance()
+            .get::<IndexLength>(&self.length_key())
+            .map(|length| length.get_length())
+            .unwrap_or(0)
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        let mut length = IndexLength::new();
+        length.set_length(len);
+        Db::instance().set(&self.length_key(), length)?;
+        Ok(())
+    }
+
+    fn position(&self, member: &[u8]) -> Option<u64> {
+        match Db::instance().get::<IndexPosition>(&self.position_key(member)) {
+            Ok(ref position) if position.get_position() > 0 => Some(position.get_position() - 1),
+            _ => None,
+        }
+    }
+
+    fn set_position(&self, member: &[u8], position: Option<u64>) -> Result<()> {
+        let mut stored = IndexPosition::new();
+        if let Some(position) = position {
+            stored.set_position(position + 1);
+        }
+        Db::instance().set(&self.position_key(member), stored)?;
+        Ok(())
+    }
+
+    fn get(&self, position: u64) -> Result<Vec<u8>> {
+        let mut entry: IndexEntry = Db::instance().get(&self.entry_key(position))?;
+        Ok(entry.take_member())
+    }
+
+    fn set_entry(&self, position: u64, member: Vec<u8>) -> Result<()> {
+        let mut entry = IndexEntry::new();
+        entry.set_member(member);
+        Db::instance().set(&self.entry_key(position), entry)?;
+        Ok(())
+    }
+
+    /// Returns true if `member` is in the index.
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.position(member).is_some()
+    }
+
+    /// Adds `member` at the end of the index, unless it's already present.
+    pub fn insert(&self, member: &[u8]) -> Result<()> {
+        if self.contains(member) {
+            return Ok(());
+        }
+
+        let len = self.len();
+        self.set_entry(len, member.to_vec())?;
+        self.set_position(member, Some(len))?;
+        self.set_len(len + 1)
+    }
+
+    /// Removes `member` from the index, if present, moving the last member into its position.
+    pub fn remove(&self, member: &[u8]) -> Result<()> {
+        let position = match self.position(member) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+
+        let last = self.len() - 1;
+        if position != last {
+            let moved = self.get(last)?;
+            self.set_position(&moved, Some(position))?;
+            self.set_entry(position, moved)?;
+        }
+        self.set_entry(last, Vec::new())?;
+        self.set_position(member, None)?;
+        self.set_len(last)
+    }
+
+    /// Returns the members at positions `start` up to, but excluding, `end`.
+    pub fn range(&self, start: u64, end: u64) -> Result<Vec<Vec<u8>>> {
+        let end = end.min(self.len());
+        let mut members = Vec::with_capacity(end.saturating_sub(start) as usize);
+        for position in start..end {
+            members.push(self.get(position)?);
+        }
+        Ok(members)
+    }
+
+    /// Returns all members.
+    pub fn members(&self) -> Result<Vec<Vec<u8>>> {
+        self.range(0, self.len())
+    }
+
+    /// Removes every member.
+    pub fn clear(&self) -> Result<()> {
+        for member in self.members()? {
+            self.set_position(&member, None)?;
+        }
+        for position in 0..self.len() {
+            self.set_entry(position, Vec::new())?;
+        }
+        self.set_len(0)
+    }
+}
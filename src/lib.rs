@@ -1,50 +1,204 @@
 #![feature(use_extern_macros)]
 #![feature(alloc)]
 
+mod block;
+mod dump;
+mod error;
 mod evm;
+mod genesis;
+mod index;
+mod logs;
+mod proof;
+mod receipt;
+mod snapshot;
+mod state;
+mod transaction;
+mod trie;
 
 extern crate protobuf;
 
 extern crate alloc;
 extern crate bigint;
 extern crate hexutil;
+extern crate rlp;
+extern crate secp256k1;
 extern crate sha3;
 extern crate sputnikvm;
+extern crate sputnikvm_network_foundation;
 
 extern crate ekiden_core_common;
 extern crate ekiden_core_trusted;
 
 extern crate evm_api;
 
-use evm_api::{with_api, CreateTokenRequest, CreateTokenResponse, EthState, GetBalanceRequest,
-                   GetBalanceResponse, InitStateRequest, InitStateResponse, TransferTokenRequest,
-                   TransferTokenResponse};
+use evm_api::{with_api, BatchResult, CallContractRequest, CallContractResponse, ChainConfig,
+              CreateTokenRequest, CreateTokenResponse, DeployContractRequest,
+              DeployContractResponse, DumpStateRequest, DumpStateResponse, GetBalanceRequest,
+              GetBalanceResponse, GetBlockByNumberRequest, GetBlockByNumberResponse,
+              GetBlockNumberRequest, GetBlockNumberResponse, GetLogsRequest, GetLogsResponse,
+              GetProofRequest, GetProofResponse, GetStateRootRequest, GetStateRootResponse,
+              GetTransactionByHashRequest, GetTransactionByHashResponse,
+              GetTransactionReceiptRequest, GetTransactionReceiptResponse, InitStateRequest,
+              InitStateResponse, LoadStateRequest, LoadStateResponse, MigrateStateRequest,
+              MigrateStateResponse, RevertToSnapshotRequest, RevertToSnapshotResponse,
+              SendRawTransactionRequest, SendRawTransactionResponse, SnapshotRequest,
+              SnapshotResponse, StaticCallRequest, StaticCallResponse, SubmitBatchRequest,
+              SubmitBatchResponse, TransferTokenRequest, TransferTokenResponse};
 
 use sputnikvm::{TransactionAction, ValidTransaction};
 
 use bigint::{Address, Gas, H256, U256};
+use protobuf::RepeatedField;
 use hexutil::{read_hex, to_hex};
 use sha3::{Digest, Keccak256};
 
-use std::str::FromStr;
+use std::collections::HashMap;
 use std::rc::Rc;
 
-use evm::fire_transactions_and_update_state;
+use block::{commit_block, get_block, init_genesis_block, latest_block, latest_block_number,
+            next_block};
+use dump::{dump_accounts, load_accounts};
+use error::EvmError;
+use evm::{account_nonce, fire_static_call, fire_transactions_and_update_state, parse_address,
+          parse_h256, parse_u256, TransactionResult};
+use genesis::{chain_config, check_dev_mode, genesis_alloc, store_chain_config};
+use logs::{find_logs, store_logs, truncate_logs};
+use proof::account_proof;
+use receipt::{get_receipt, make_receipts, store_receipts};
+use snapshot::{discard_snapshots, restore_snapshot, take_snapshot};
+use state::{apply_changes, check_state_version, clear as clear_state, migrate, state_root,
+            state_version};
+use transaction::{check_nonce, check_request_signature, check_signed_transaction,
+                  create_token_hash, decode_signed_transaction, get_transaction,
+                  store_transactions, transfer_hash, HashedTransaction};
 
 use ekiden_core_common::Result;
-use ekiden_core_trusted::db::Db;
 use ekiden_core_trusted::rpc::create_enclave_rpc;
 
+/// Intrinsic gas of a transaction without data, the least any transaction uses.
+const TRANSACTION_GAS: u64 = 21_000;
+
 // Create enclave RPC handlers.
 with_api! {
     create_enclave_rpc!(api);
 }
 
+/// Parses a decimal wei amount from a request. An empty string means zero.
+fn parse_value(value: &str) -> Result<U256> {
+    if value.is_empty() {
+        Ok(U256::zero())
+    } else {
+        Ok(parse_u256(value)?)
+    }
+}
+
+/// Gas limit and price of a transaction.
+struct GasSettings {
+    limit: Gas,
+    price: Gas,
+}
+
+/// Parses the gas settings of a request. A zero gas limit means the block gas limit, and an
+/// empty gas price means zero.
+fn parse_gas(gas_limit: u64, gas_price: &str) -> Result<GasSettings> {
+    let gas_limit = if gas_limit == 0 {
+        latest_block()?.get_gas_limit()
+    } else {
+        gas_limit
+    };
+
+    Ok(GasSettings {
+        limit: Gas::from(gas_limit),
+        price: Gas::from(parse_value(gas_price)?),
+    })
+}
+
+/// Builds a transaction from `caller` with the given nonce, calling `to` with `input` as calldata,
+/// or deploying a contract with `input` as init code if `to` is `None`.
+fn transaction_with_nonce(
+    caller: Address,
+    to: Option<Address>,
+    input: Vec<u8>,
+    value: U256,
+    gas: GasSettings,
+    nonce: U256,
+) -> ValidTransaction {
+    ValidTransaction {
+        caller: Some(caller),
+        action: match to {
+            Some(contract) => TransactionAction::Call(contract),
+            None => TransactionAction::Create,
+        },
+        gas_price: gas.price,
+        gas_limit: gas.limit,
+        value: value,
+        input: Rc::new(input),
+        nonce: nonce,
+    }
+}
+
+/// Executes `transactions` in a new block, then persists the state changes, the block, the
+/// transactions with a receipt for each, and the logs they emitted. Returns the outcome of each
+/// transaction in order, and whether they were persisted: if `atomic` is set and any of them
+/// fails, nothing is.
+fn execute(
+    transactions: &[HashedTransaction],
+    atomic: bool,
+) -> Result<(Vec<TransactionResult>, bool)> {
+    check_state_version()?;
+
+    let parent = latest_block()?;
+    let block = next_block(&parent)?;
+
+    let (changes, results) = fire_transactions_and_update_state(transactions, &block)?;
+    if atomic && results.iter().any(|result| !result.status) {
+        return Ok((results, false));
+    }
+    let receipts = make_receipts(&results);
+
+    let state_root = apply_changes(changes)?;
+    commit_block(&parent, &block.header, &results, &state_root)?;
+    store_transactions(transactions, block.header.number.as_u64())?;
+    store_receipts(&receipts)?;
+    store_logs(&receipts)?;
+    Ok((results, true))
+}
+
+/// Executes a single transaction. See `execute`.
+fn execute_transaction(transaction: HashedTransaction) -> Result<TransactionResult> {
+    let (mut results, _) = execute(&[transaction], false)?;
+    Ok(results.remove(0))
+}
+
+/// Calls `contract` without modifying the state and returns the call's output and the gas it
+/// used. The call is free, but still limited to `gas_limit`.
+fn read_only_call(
+    caller: Address,
+    contract: Address,
+    input: Vec<u8>,
+    gas_limit: u64,
+) -> Result<(Vec<u8>, Gas)> {
+    let transaction = ValidTransaction {
+        caller: Some(caller),
+        action: TransactionAction::Call(contract),
+        gas_price: Gas::zero(),
+        gas_limit: parse_gas(gas_limit, "")?.limit,
+        value: U256::zero(),
+        input: Rc::new(input),
+        nonce: U256::zero(),
+    };
+
+    check_state_version()?;
+
+    // Read-only calls see the state as it would be at the start of the next block.
+    let block = next_block(&latest_block()?)?;
+    Ok(fire_static_call(transaction, &block)?)
+}
+
 fn create(request: &CreateTokenRequest) -> Result<CreateTokenResponse> {
-    let state = Db::instance().get("state")?;
     println!("create creator={}", request.get_creator_address());
 
-    let creator_addr = Address::from_str(request.get_creator_address()).unwrap();
+    let creator_addr = parse_address(request.get_creator_address())?;
 
     // EVM bytecode for ERC20 token contract (from https://ethereum.org/token) with the following parameters:
     //
@@ -59,48 +213,81 @@ fn create(request: &CreateTokenRequest) -> Result<CreateTokenResponse> {
     // Add remaining constructor parameters (tokenName, tokenSymbol).
     bytecode.extend_from_slice(&read_hex("0x000000000000000000000000000000000000000000000000000000000000006000000000000000000000000000000000000000000000000000000000000000a00000000000000000000000000000000000000000000000000000000000000004546573740000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000035453540000000000000000000000000000000000000000000000000000000000").unwrap());
 
-    let transactions = [
-        ValidTransaction {
-            caller: Some(creator_addr),
-            action: TransactionAction::Create,
-            gas_price: Gas::zero(),
-            gas_limit: Gas::max_value(),
-            value: U256::zero(),
-            input: Rc::new(bytecode),
-            nonce: U256::zero(),
-        },
-    ];
+    let nonce = U256::from(request.get_nonce());
+    check_nonce(&creator_addr, &nonce)?;
+    let gas = parse_gas(request.get_gas_limit(), request.get_gas_price())?;
+
+    // Only the creator can create a token in their name, and spend their balance on its fee, by
+    // signing the request.
+    let hash = create_token_hash(
+        &U256::from(request.get_initial_supply()),
+        &nonce,
+        gas.limit,
+        gas.price,
+        chain_config()?.get_chain_id(),
+    );
+    check_request_signature(&creator_addr, &hash, request.get_signature())?;
 
-    let (new_state, _) = fire_transactions_and_update_state(&transactions, &state);
+    let transaction =
+        transaction_with_nonce(creator_addr, None, bytecode, U256::zero(), gas, nonce);
 
-    // Compute address of new token contract. In practice, a web3 client handling a "create" action
-    // returns a transaction hash, and the caller needs to wait until the next block is mined to
-    // retrieve the contract's address. For simplicity, we manually compute the address and return
-    // it immediately. The address is a function of the caller and nonce (see https://ethereum.stackexchange.com/questions/760/how-is-the-address-of-an-ethereum-contract-computed)
+    // In practice, a web3 client handling a "create" action returns a transaction hash, and the
+    // caller needs to wait until the next block is mined to retrieve the contract's address. For
+    // simplicity, we return the address of the account the VM created immediately. The address is
+    // a function of the caller and nonce (see https://ethereum.stackexchange.com/questions/760/how-is-the-address-of-an-ethereum-contract-computed)
     //
-    let token_contract_addr = {
-        let mut vec = read_hex("0xd694").unwrap().to_vec();
-        vec.extend_from_slice(&creator_addr);
-        vec.extend_from_slice(&[0x80]);
-        to_hex(&Keccak256::digest(&vec)[12..])
-    };
+    let result = execute_transaction(HashedTransaction::unsigned(transaction))?;
 
     let mut response = CreateTokenResponse::new();
-    response.set_contract_address(token_contract_addr.clone());
+    if let Some(token_contract_addr) = result.contract_address {
+        response.set_contract_address(to_hex(&token_contract_addr));
+    }
+    response.set_gas_used(result.gas_used.as_u64());
+    response.set_transaction_hash(to_hex(&result.hash));
 
-    Db::instance().set("state", new_state)?;
     Ok(response)
 }
 
-fn transfer(request: &TransferTokenRequest) -> Result<TransferTokenResponse> {
-    let state = Db::instance().get("state")?;
+fn deploy_contract(request: &DeployContractRequest) -> Result<DeployContractResponse> {
+    println!("deploy_contract caller={}", request.get_caller_address());
+
+    let caller = parse_address(request.get_caller_address())?;
+    let value = parse_value(request.get_value())?;
+
+    // Constructor arguments are ABI-encoded and appended to the init code, just like the token
+    // parameters in create().
+    let mut init_code = request.get_code().to_vec();
+    init_code.extend_from_slice(request.get_constructor_data());
+
+    let nonce = U256::from(request.get_nonce());
+    check_nonce(&caller, &nonce)?;
+    let gas = parse_gas(request.get_gas_limit(), request.get_gas_price())?;
+    let transaction = transaction_with_nonce(caller, None, init_code, value, gas, nonce);
+
+    // Only the caller can deploy in their name, and spend their balance, by signing the
+    // transaction.
+    let chain_id = chain_config()?.get_chain_id();
+    let transaction = HashedTransaction::signed(transaction, chain_id, request.get_signature())?;
+    let result = execute_transaction(transaction)?;
+
+    let mut response = DeployContractResponse::new();
+    if let Some(contract_addr) = result.contract_address {
+        response.set_contract_address(to_hex(&contract_addr));
+    }
+    response.set_gas_used(result.gas_used.as_u64());
+    response.set_status(result.status);
+    response.set_transaction_hash(to_hex(&result.hash));
 
+    Ok(response)
+}
+
+fn transfer(request: &TransferTokenRequest) -> Result<TransferTokenResponse> {
     println!(
         "transfer amount={}, from={}, to={}",
         request.amount, request.from_address, request.to_address
     );
 
-    let to_addr = Address::from_str(request.get_to_address()).unwrap();
+    let to_addr = parse_address(request.get_to_address())?;
 
     // Construct the EVM payload for this transaction.
     //
@@ -115,62 +302,369 @@ fn transfer(request: &TransferTokenRequest) -> Result<TransferTokenResponse> {
     payload.extend_from_slice(&H256::from(to_addr));
     payload.extend_from_slice(&H256::from(request.get_amount()));
 
-    let caller = Address::from_str(request.get_from_address()).unwrap();
-    let contract_addr = Address::from_str(request.get_contract_address()).unwrap();
-
-    let transactions = [
-        ValidTransaction {
-            caller: Some(caller),
-            action: TransactionAction::Call(contract_addr),
-            gas_price: Gas::zero(),
-            gas_limit: Gas::max_value(),
-            value: U256::zero(),
-            input: Rc::new(payload),
-            nonce: U256::zero(),
-        },
-    ];
+    let caller = parse_address(request.get_from_address())?;
+    let contract_addr = parse_address(request.get_contract_address())?;
+    let nonce = U256::from(request.get_nonce());
+    check_nonce(&caller, &nonce)?;
+    let gas = parse_gas(request.get_gas_limit(), request.get_gas_price())?;
+
+    // Only the holder of the tokens can move them, by signing the transfer.
+    let hash = transfer_hash(
+        &contract_addr,
+        &to_addr,
+        &U256::from(request.get_amount()),
+        &nonce,
+        gas.limit,
+        gas.price,
+        chain_config()?.get_chain_id(),
+    );
+    check_request_signature(&caller, &hash, request.get_signature())?;
+
+    let contract = Some(contract_addr);
+    let transaction = transaction_with_nonce(caller, contract, payload, U256::zero(), gas, nonce);
+    let result = execute_transaction(HashedTransaction::unsigned(transaction))?;
+
+    let mut response = TransferTokenResponse::new();
+    response.set_status(result.status);
+    response.set_gas_used(result.gas_used.as_u64());
+    response.set_transaction_hash(to_hex(&result.hash));
+
+    Ok(response)
+}
+
+fn call_contract(request: &CallContractRequest) -> Result<CallContractResponse> {
+    println!(
+        "call_contract caller={}, contract={}",
+        request.get_caller_address(),
+        request.get_contract_address()
+    );
+
+    let caller = parse_address(request.get_caller_address())?;
+    let contract_addr = parse_address(request.get_contract_address())?;
+    let value = parse_value(request.get_value())?;
+    let nonce = U256::from(request.get_nonce());
+    check_nonce(&caller, &nonce)?;
+    let gas = parse_gas(request.get_gas_limit(), request.get_gas_price())?;
+
+    let transaction = transaction_with_nonce(
+        caller,
+        Some(contract_addr),
+        request.get_data().to_vec(),
+        value,
+        gas,
+        nonce,
+    );
+
+    // Only the caller can call in their name, and spend their balance, by signing the
+    // transaction.
+    let chain_id = chain_config()?.get_chain_id();
+    let transaction = HashedTransaction::signed(transaction, chain_id, request.get_signature())?;
+    let result = execute_transaction(transaction)?;
+
+    let mut response = CallContractResponse::new();
+    response.set_output(result.output);
+    response.set_gas_used(result.gas_used.as_u64());
+    response.set_status(result.status);
+    response.set_transaction_hash(to_hex(&result.hash));
+
+    Ok(response)
+}
 
-    let (new_state, _) = fire_transactions_and_update_state(&transactions, &state);
-    let response = TransferTokenResponse::new();
+fn send_raw_transaction(request: &SendRawTransactionRequest) -> Result<SendRawTransactionResponse> {
+    let signed = decode_signed_transaction(request.get_data())?;
+    println!("send_raw_transaction sender={}", to_hex(&signed.sender));
+
+    check_signed_transaction(&signed, chain_config()?.get_chain_id())?;
+    let result = execute_transaction(HashedTransaction {
+        hash: signed.hash,
+        transaction: signed.transaction,
+    })?;
+
+    let mut response = SendRawTransactionResponse::new();
+    response.set_transaction_hash(to_hex(&result.hash));
+    response.set_status(result.status);
+    response.set_gas_used(result.gas_used.as_u64());
+    response.set_output(result.output);
+    if let Some(contract_addr) = result.contract_address {
+        response.set_contract_address(to_hex(&contract_addr));
+    }
+
+    Ok(response)
+}
+
+fn submit_batch(request: &SubmitBatchRequest) -> Result<SubmitBatchResponse> {
+    println!("submit_batch transactions={}", request.get_transactions().len());
+
+    if request.get_transactions().is_empty() {
+        return Ok(SubmitBatchResponse::new());
+    }
+    // Every transaction uses at least TRANSACTION_GAS, so a larger batch can't fit in a block.
+    // Conversely, a share of the block gas limit covers that much for every transaction.
+    let block_gas_limit = latest_block()?.get_gas_limit();
+    let count = request.get_transactions().len() as u64;
+    if count > block_gas_limit / TRANSACTION_GAS {
+        return Err(EvmError::BlockGasLimitExceeded.into());
+    }
+    let gas_share = block_gas_limit / count;
+    let chain_id = chain_config()?.get_chain_id();
+
+    // Nonces aren't updated in state until the batch is committed, so they are tracked here.
+    let mut nonces: HashMap<Address, U256> = HashMap::new();
+    let mut transactions = Vec::with_capacity(request.get_transactions().len());
+    for item in request.get_transactions() {
+        let caller = parse_address(item.get_caller_address())?;
+        let to = if item.get_contract_address().is_empty() {
+            None
+        } else {
+            Some(parse_address(item.get_contract_address())?)
+        };
+        let value = parse_value(item.get_value())?;
+        let gas_limit = if item.get_gas_limit() == 0 {
+            gas_share
+        } else {
+            item.get_gas_limit()
+        };
+        let gas = parse_gas(gas_limit, item.get_gas_price())?;
+
+        let nonce = match nonces.get(&caller) {
+            Some(nonce) => *nonce,
+            None => account_nonce(&caller)?,
+        };
+        if U256::from(item.get_nonce()) != nonce {
+            return Err(EvmError::InvalidNonce(caller).into());
+        }
+        nonces.insert(caller, nonce + U256::one());
+
+        let input = item.get_data().to_vec();
+        let transaction = transaction_with_nonce(caller, to, input, value, gas, nonce);
+        // As in call_contract, the caller must sign each transaction made in their name.
+        transactions.push(HashedTransaction::signed(transaction, chain_id, item.get_signature())?);
+    }
+
+    let (results, committed) = execute(&transactions, true)?;
+
+    let mut response = SubmitBatchResponse::new();
+    for result in results {
+        let mut batch_result = BatchResult::new();
+        batch_result.set_transaction_hash(to_hex(&result.hash));
+        batch_result.set_status(result.status);
+        batch_result.set_gas_used(result.gas_used.as_u64());
+        batch_result.set_output(result.output);
+        if let Some(contract_addr) = result.contract_address {
+            batch_result.set_contract_address(to_hex(&contract_addr));
+        }
+        response.mut_results().push(batch_result);
+    }
+    response.set_committed(committed);
 
-    Db::instance().set("state", new_state)?;
     Ok(response)
 }
 
 fn get_balance(request: &GetBalanceRequest) -> Result<GetBalanceResponse> {
-    let state = Db::instance().get("state")?;
     println!("get_balance addr={}", request.get_address());
 
-    let address = Address::from_str(request.get_address()).unwrap();
-    let contract_addr = Address::from_str(request.get_contract_address()).unwrap();
+    let address = parse_address(request.get_address())?;
+    let contract_addr = parse_address(request.get_contract_address())?;
 
     // Construct the EVM payload for this transaction. See comment in transfer_tokens() for explanation.
     let mut payload = Keccak256::digest("balanceOf(address)".as_bytes()).as_slice()[..4].to_vec();
     payload.extend_from_slice(&H256::from(address));
 
-    let transactions = [
-        ValidTransaction {
-            caller: Some(Address::default()),
-            action: TransactionAction::Call(contract_addr),
-            gas_price: Gas::zero(),
-            gas_limit: Gas::max_value(),
-            value: U256::zero(),
-            input: Rc::new(payload),
-            nonce: U256::zero(),
-        },
-    ];
+    let (result, _) = read_only_call(Address::default(), contract_addr, payload, 0)?;
 
-    let (_, result) = fire_transactions_and_update_state(&transactions, &state);
+    // balanceOf returns a single uint256, which may not fit in 64 bits.
+    if result.len() != 32 {
+        return Err(EvmError::InvalidReturnData(contract_addr).into());
+    }
 
     let mut response = GetBalanceResponse::new();
-    let result_as_u64 = U256::from(result.as_slice()).as_u64();
-    response.set_balance(result_as_u64);
+    response.set_balance(format!("{}", U256::from(result.as_slice())));
+
+    Ok(response)
+}
+
+fn static_call(request: &StaticCallRequest) -> Result<StaticCallResponse> {
+    println!("static_call contract={}", request.get_contract_address());
 
+    let caller = if request.get_caller_address().is_empty() {
+        Address::default()
+    } else {
+        parse_address(request.get_caller_address())?
+    };
+    let contract_addr = parse_address(request.get_contract_address())?;
+
+    let (output, gas_used) = read_only_call(
+        caller,
+        contract_addr,
+        request.get_data().to_vec(),
+        request.get_gas_limit(),
+    )?;
+
+    let mut response = StaticCallResponse::new();
+    response.set_output(output);
+    response.set_gas_used(gas_used.as_u64());
+    Ok(response)
+}
+
+fn get_transaction_by_hash(
+    request: &GetTransactionByHashRequest,
+) -> Result<GetTransactionByHashResponse> {
+    println!("get_transaction_by_hash hash={}", request.get_transaction_hash());
+
+    let transaction_hash = parse_h256(request.get_transaction_hash())?;
+
+    let mut response = GetTransactionByHashResponse::new();
+    response.set_transaction(get_transaction(&transaction_hash)?);
+    Ok(response)
+}
+
+fn get_transaction_receipt(
+    request: &GetTransactionReceiptRequest,
+) -> Result<GetTransactionReceiptResponse> {
+    println!("get_transaction_receipt hash={}", request.get_transaction_hash());
+
+    let transaction_hash = parse_h256(request.get_transaction_hash())?;
+
+    let mut response = GetTransactionReceiptResponse::new();
+    response.set_receipt(get_receipt(&transaction_hash)?);
+    Ok(response)
+}
+
+fn get_logs(request: &GetLogsRequest) -> Result<GetLogsResponse> {
+    println!(
+        "get_logs from_block={}, to_block={}",
+        request.get_from_block(),
+        request.get_to_block()
+    );
+
+    let mut response = GetLogsResponse::new();
+    response.set_logs(RepeatedField::from_vec(find_logs(request)?));
+    Ok(response)
+}
+
+fn get_block_number(_request: &GetBlockNumberRequest) -> Result<GetBlockNumberResponse> {
+    let mut response = GetBlockNumberResponse::new();
+    response.set_number(latest_block_number()?);
+    Ok(response)
+}
+
+fn get_block_by_number(request: &GetBlockByNumberRequest) -> Result<GetBlockByNumberResponse> {
+    println!("get_block_by_number number={}", request.get_number());
+
+    let mut response = GetBlockByNumberResponse::new();
+    response.set_block(get_block(request.get_number())?);
+    Ok(response)
+}
+
+fn get_state_root(_request: &GetStateRootRequest) -> Result<GetStateRootResponse> {
+    let mut response = GetStateRootResponse::new();
+    response.set_state_root(to_hex(&state_root()?));
+    Ok(response)
+}
+
+fn get_proof(request: &GetProofRequest) -> Result<GetProofResponse> {
+    println!("get_proof address={}", request.get_address());
+
+    let address = parse_address(request.get_address())?;
+    let mut storage_keys = Vec::with_capacity(request.get_storage_keys().len());
+    for key in request.get_storage_keys() {
+        storage_keys.push(parse_h256(key)?);
+    }
+
+    Ok(account_proof(&address, &storage_keys)?)
+}
+
+fn snapshot(_request: &SnapshotRequest) -> Result<SnapshotResponse> {
+    check_dev_mode()?;
+    let id = take_snapshot()?;
+    println!("snapshot id={}", id);
+
+    let mut response = SnapshotResponse::new();
+    response.set_id(id);
+    Ok(response)
+}
+
+fn revert_to_snapshot(request: &RevertToSnapshotRequest) -> Result<RevertToSnapshotResponse> {
+    println!("revert_to_snapshot id={}", request.get_id());
+
+    check_dev_mode()?;
+    restore_snapshot(request.get_id())?;
+    Ok(RevertToSnapshotResponse::new())
+}
+
+fn dump_state(request: &DumpStateRequest) -> Result<DumpStateResponse> {
+    println!("dump_state start={}, limit={}", request.get_start(), request.get_limit());
+
+    check_state_version()?;
+    Ok(dump_accounts(request.get_start(), request.get_limit())?)
+}
+
+fn load_state(request: &LoadStateRequest) -> Result<LoadStateResponse> {
+    println!(
+        "load_state accounts={}, reset={}",
+        request.get_accounts().len(),
+        request.get_reset()
+    );
+
+    check_dev_mode()?;
+    if request.get_reset() {
+        clear_state()?;
+    } else {
+        check_state_version()?;
+    }
+    let state_root = load_accounts(request.get_accounts())?;
+
+    let mut response = LoadStateResponse::new();
+    response.set_state_root(to_hex(&state_root));
     Ok(response)
 }
 
-fn init_genesis_state(_request: &InitStateRequest) -> Result<InitStateResponse> {
+fn init_genesis_state(request: &InitStateRequest) -> Result<InitStateResponse> {
+    let beneficiary = if request.get_coinbase().is_empty() {
+        Address::default()
+    } else {
+        parse_address(request.get_coinbase())?
+    };
+
+    println!(
+        "init_genesis_state fork={:?}, chain_id={}, alloc={}, dev_mode={}",
+        request.get_fork(),
+        request.get_chain_id(),
+        request.get_alloc().len(),
+        request.get_dev_mode()
+    );
+
+    // Initializing the chain again discards it, so only development chains allow it.
+    check_dev_mode()?;
+
+    let mut config = ChainConfig::new();
+    config.set_fork(request.get_fork());
+    config.set_chain_id(request.get_chain_id());
+    config.set_dev_mode(request.get_dev_mode());
+    store_chain_config(config)?;
+
     let response = InitStateResponse::new();
-    Db::instance().set("state", EthState::new())?;
+    clear_state()?;
+    let state_root = apply_changes(genesis_alloc(request.get_alloc())?)?;
+    init_genesis_block(request.get_timestamp(), &beneficiary, &state_root)?;
+    truncate_logs(0)?;
+    discard_snapshots()?;
+    Ok(response)
+}
+
+fn migrate_state(_request: &MigrateStateRequest) -> Result<MigrateStateResponse> {
+    let from_version = migrate()?;
+    println!("migrate_state from_version={}", from_version);
+
+    // States written before the chain was tracked have no chain configuration or blocks. They
+    // keep the original rules, and the migrated state becomes the genesis block.
+    if chain_config().is_err() {
+        store_chain_config(ChainConfig::new())?;
+        init_genesis_block(0, &Address::default(), &state_root()?)?;
+    }
+
+    let mut response = MigrateStateResponse::new();
+    response.set_from_version(from_version);
+    response.set_to_version(state_version());
     Ok(response)
 }
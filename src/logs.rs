@@ -0,0 +1,196 @@
+use std::collections::BTreeSet;
+
+use hexutil::to_hex;
+
+use evm::{parse_address, parse_h256};
+use evm_api::{GetLogsRequest, LogCount, LogEntry, TransactionReceipt};
+use index::Index;
+
+use ekiden_core_common::Result;
+use ekiden_core_trusted::db::Db;
+
+// Logs are stored one per key under a sequential id, with an index of ids for every contract
+// address and every topic that appears in a log. Blocks are executed in order, so block numbers
+// never decrease from one id to the next.
+const LOG_COUNT_KEY: &str = "log_count";
+
+fn log_key(id: u64) -> String {
+    format!("log:{}", id)
+}
+
+fn address_index(address: &str) -> Index {
+    Index::new(format!("log_index:address:{}", address))
+}
+
+fn topic_index(topic: &str) -> Index {
+    Index::new(format!("log_index:topic:{}", topic))
+}
+
+/// Returns the indexes a log is listed in. A topic may appear in several positions of the same
+/// log, but is only indexed once.
+fn log_indexes(entry: &LogEntry) -> Vec<Index> {
+    let mut topics: Vec<&String> = entry.get_topics().iter().collect();
+    topics.sort();
+    topics.dedup();
+
+    let mut indexes = vec![address_index(entry.get_address())];
+    indexes.extend(topics.into_iter().map(|topic| topic_index(topic)));
+    indexes
+}
+
+fn encode_log_id(id: u64) -> Vec<u8> {
+    (0..8).rev().map(|byte| (id >> (8 * byte)) as u8).collect()
+}
+
+fn decode_log_id(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |id, &byte| (id << 8) | byte as u64)
+}
+
+/// Returns the ids of the logs listed in an index.
+fn indexed_log_ids(index: &Index) -> Result<Vec<u64>> {
+    Ok(index.members()?.iter().map(|member| decode_log_id(member)).collect())
+}
+
+/// Returns the number of logs stored so far.
+pub fn log_count() -> u64 {
+    Db::instance()
+        .get::<LogCount>(LOG_COUNT_KEY)
+        .map(|count| count.get_count())
+        .unwrap_or(0)
+}
+
+fn set_log_count(count: u64) -> Result<()> {
+    let mut log_count = LogCount::new();
+    log_count.set_count(count);
+    Db::instance().set(LOG_COUNT_KEY, log_count)
+}
+
+/// Discards all logs but the first `count`, and removes them from the indexes. Their ids are then
+/// reused by new logs.
+pub fn truncate_logs(count: u64) -> Result<()> {
+    for id in count..log_count() {
+        let entry: LogEntry = Db::instance().get(&log_key(id))?;
+        for index in log_indexes(&entry) {
+            index.remove(&encode_log_id(id))?;
+        }
+    }
+
+    set_log_count(count)
+}
+
+/// Persists and indexes the logs contained in `receipts`.
+pub fn store_logs(receipts: &[TransactionReceipt]) -> Result<()> {
+    let mut count = log_count();
+
+    for entry in receipts.iter().flat_map(|receipt| receipt.get_logs()) {
+        Db::instance().set(&log_key(count), entry.clone())?;
+        for index in log_indexes(entry) {
+            index.insert(&encode_log_id(count))?;
+        }
+        count += 1;
+    }
+
+    set_log_count(count)
+}
+
+/// Returns the id of the first log emitted in block `number` or later, or the log count if there
+/// is none.
+fn first_log_from_block(db: &Db, count: u64, number: u64) -> Result<u64> {
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let entry: LogEntry = db.get(&log_key(middle))?;
+        if entry.get_block_number() < number {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    Ok(low)
+}
+
+/// Checks whether a log satisfies a filter. `topics[i]` lists the values accepted at position
+/// `i`; an empty list accepts anything.
+fn matches(
+    entry: &LogEntry,
+    addresses: &[String],
+    topics: &[Vec<String>],
+    from_block: u64,
+    to_block: u64,
+) -> bool {
+    if entry.get_block_number() < from_block {
+        return false;
+    }
+    if to_block != 0 && entry.get_block_number() > to_block {
+        return false;
+    }
+    if !addresses.is_empty() && !addresses.iter().any(|a| a == entry.get_address()) {
+        return false;
+    }
+
+    topics
+        .iter()
+        .enumerate()
+        .all(|(position, accepted)| match entry.get_topics().get(position) {
+            _ if accepted.is_empty() => true,
+            Some(topic) => accepted.contains(topic),
+            None => false,
+        })
+}
+
+/// Returns the stored logs matching a filter, in the order they were emitted.
+pub fn find_logs(request: &GetLogsRequest) -> Result<Vec<LogEntry>> {
+    // Normalize addresses and topics so they compare equal to the stored hex strings.
+    let mut addresses = Vec::new();
+    for address in request.get_addresses() {
+        addresses.push(to_hex(&parse_address(address)?));
+    }
+    let mut topics = Vec::new();
+    for filter in request.get_topics() {
+        let mut accepted = Vec::new();
+        for topic in filter.get_topics() {
+            accepted.push(to_hex(&parse_h256(topic)?));
+        }
+        topics.push(accepted);
+    }
+
+    // Use the indexes to narrow down the logs to look at: any matching log must be indexed under
+    // one of the addresses, and under one of the topics accepted at each constrained position.
+    // Without such a constraint, look at the ids within the requested block range instead.
+    let mut candidates = BTreeSet::new();
+    if !addresses.is_empty() {
+        for address in &addresses {
+            candidates.extend(indexed_log_ids(&address_index(address))?);
+        }
+    } else if let Some(accepted) = topics.iter().find(|accepted| !accepted.is_empty()) {
+        for topic in accepted {
+            candidates.extend(indexed_log_ids(&topic_index(topic))?);
+        }
+    } else {
+        let count = log_count();
+        let db = Db::instance();
+        let start = first_log_from_block(&db, count, request.get_from_block())?;
+        let end = match request.get_to_block() {
+            0 => count,
+            to_block => first_log_from_block(&db, count, to_block.saturating_add(1))?,
+        };
+        candidates.extend(start..end);
+    }
+
+    let db = Db::instance();
+    let mut logs = Vec::new();
+    for id in candidates {
+        let entry: LogEntry = db.get(&log_key(id))?;
+        if matches(
+            &entry,
+            &addresses,
+            &topics,
+            request.get_from_block(),
+            request.get_to_block(),
+        ) {
+            logs.push(entry);
+        }
+    }
+
+    Ok(logs)
+}
@@ -0,0 +1,52 @@
+use bigint::{Address, H256, U256};
+use hexutil::to_hex;
+use protobuf::RepeatedField;
+use sha3::{Digest, Keccak256};
+
+use error::Result;
+use evm_api::{GetProofResponse, StorageProof};
+use state::{code_hash, decode_u256, empty_code_hash, get_account, get_storage, state_root,
+            storage_root};
+use trie::{empty_root, prove};
+
+/// Builds an EIP-1186 proof of the account at `address` and of the given storage slots against
+/// the current state root. The account proof shows the account's trie entry, or its absence, in
+/// the state trie, and each storage proof does the same for a slot in the account's storage trie.
+pub fn account_proof(address: &Address, storage_keys: &[H256]) -> Result<GetProofResponse> {
+    let root = state_root()?;
+    let (_, proof) = prove(root, &Keccak256::digest(address))?;
+
+    // A missing account is reported with the fields of an empty account.
+    let (nonce, balance, code_hash, storage_hash) = match get_account(address) {
+        Some(ref account) => (
+            decode_u256(account.get_nonce())?,
+            decode_u256(account.get_balance())?,
+            code_hash(account)?,
+            storage_root(account)?,
+        ),
+        None => (U256::zero(), U256::zero(), empty_code_hash(), empty_root()),
+    };
+
+    let mut storage_proofs = Vec::with_capacity(storage_keys.len());
+    for key in storage_keys {
+        let (_, proof) = prove(storage_hash, &Keccak256::digest(key))?;
+        let value: U256 = get_storage(address, &U256::from(&key[..]))?.into();
+
+        let mut storage_proof = StorageProof::new();
+        storage_proof.set_key(to_hex(key));
+        storage_proof.set_value(format!("{}", value));
+        storage_proof.set_proof(RepeatedField::from_vec(proof));
+        storage_proofs.push(storage_proof);
+    }
+
+    let mut response = GetProofResponse::new();
+    response.set_address(to_hex(address));
+    response.set_nonce(format!("{}", nonce));
+    response.set_balance(format!("{}", balance));
+    response.set_code_hash(to_hex(&code_hash));
+    response.set_storage_hash(to_hex(&storage_hash));
+    response.set_account_proof(RepeatedField::from_vec(proof));
+    response.set_storage_proof(RepeatedField::from_vec(storage_proofs));
+    response.set_state_root(to_hex(&root));
+    Ok(response)
+}
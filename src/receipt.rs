@@ -0,0 +1,82 @@
+use bigint::{H256, U256};
+use hexutil::to_hex;
+use protobuf::RepeatedField;
+
+use block::transaction_in_chain;
+use error::EvmError;
+use evm::TransactionResult;
+use evm_api::{LogEntry, TransactionReceipt};
+
+use ekiden_core_common::Result;
+use ekiden_core_trusted::db::Db;
+
+fn receipt_key(transaction_hash: &str) -> String {
+    format!("receipt:{}", transaction_hash)
+}
+
+/// Builds receipts for the transactions executed in a block, in execution order.
+pub fn make_receipts(results: &[TransactionResult]) -> Vec<TransactionReceipt> {
+    let mut cumulative_gas_used = U256::zero();
+    // Logs are numbered by their position in the block.
+    let mut log_index = 0;
+
+    results
+        .iter()
+        .map(|result| {
+            let gas_used: U256 = result.gas_used.into();
+            cumulative_gas_used = cumulative_gas_used + gas_used;
+
+            let logs = result
+                .logs
+                .iter()
+                .map(|log| {
+                    let mut entry = LogEntry::new();
+                    entry.set_address(to_hex(&log.address));
+                    entry.set_topics(RepeatedField::from_vec(
+                        log.topics.iter().map(|topic| to_hex(topic)).collect(),
+                    ));
+                    entry.set_data(log.data.to_vec());
+                    entry.set_block_number(result.block_number.as_u64());
+                    entry.set_transaction_hash(to_hex(&result.hash));
+                    entry.set_log_index(log_index);
+                    log_index += 1;
+                    entry
+                })
+                .collect();
+
+            let mut receipt = TransactionReceipt::new();
+            receipt.set_transaction_hash(to_hex(&result.hash));
+            receipt.set_block_number(result.block_number.as_u64());
+            receipt.set_status(result.status);
+            receipt.set_cumulative_gas_used(cumulative_gas_used.as_u64());
+            receipt.set_gas_used(gas_used.as_u64());
+            if let Some(address) = result.contract_address {
+                receipt.set_contract_address(to_hex(&address));
+            }
+            receipt.set_logs(RepeatedField::from_vec(logs));
+            receipt
+        })
+        .collect()
+}
+
+/// Persists receipts, keyed by transaction hash.
+pub fn store_receipts(receipts: &[TransactionReceipt]) -> Result<()> {
+    let mut db = Db::instance();
+    for receipt in receipts {
+        db.set(&receipt_key(receipt.get_transaction_hash()), receipt.clone())?;
+    }
+    Ok(())
+}
+
+/// Looks up the receipt of a previously executed transaction.
+pub fn get_receipt(transaction_hash: &H256) -> Result<TransactionReceipt> {
+    let hash = to_hex(transaction_hash);
+    let receipt: TransactionReceipt = Db::instance()
+        .get(&receipt_key(&hash))
+        .map_err(|_| EvmError::TransactionNotFound(*transaction_hash))?;
+
+    if !transaction_in_chain(receipt.get_block_number(), &hash) {
+        return Err(EvmError::TransactionNotFound(*transaction_hash).into());
+    }
+    Ok(receipt)
+}
@@ -0,0 +1,61 @@
+use block::{latest_block_number, rewind_chain};
+use error::EvmError;
+use evm_api::{Snapshot, SnapshotCount};
+use logs::{log_count, truncate_logs};
+use state::{export_state, import_state};
+
+use ekiden_core_common::Result;
+use ekiden_core_trusted::db::Db;
+
+// Snapshots are numbered sequentially. Like ganache's `evm_snapshot` and `evm_revert`, reverting
+// to a snapshot discards it along with every snapshot taken after it, so ids are reused.
+const SNAPSHOT_COUNT_KEY: &str = "snapshot_count";
+
+fn snapshot_key(id: u64) -> String {
+    format!("snapshot:{}", id)
+}
+
+fn snapshot_count() -> u64 {
+    Db::instance()
+        .get::<SnapshotCount>(SNAPSHOT_COUNT_KEY)
+        .map(|count| count.get_count())
+        .unwrap_or(0)
+}
+
+fn set_snapshot_count(count: u64) -> Result<()> {
+    let mut snapshot_count = SnapshotCount::new();
+    snapshot_count.set_count(count);
+    Db::instance().set(SNAPSHOT_COUNT_KEY, snapshot_count)
+}
+
+/// Saves the current state and chain, and returns the id of the snapshot.
+pub fn take_snapshot() -> Result<u64> {
+    let mut snapshot = Snapshot::new();
+    snapshot.set_state(export_state()?);
+    snapshot.set_block_number(latest_block_number()?);
+    snapshot.set_log_count(log_count());
+
+    let id = snapshot_count();
+    Db::instance().set(&snapshot_key(id), snapshot)?;
+    set_snapshot_count(id + 1)?;
+    Ok(id)
+}
+
+/// Restores the state and chain saved by snapshot `id`. Blocks, receipts and logs produced since
+/// then are discarded.
+pub fn restore_snapshot(id: u64) -> Result<()> {
+    if id >= snapshot_count() {
+        return Err(EvmError::UnknownSnapshot(id).into());
+    }
+
+    let mut snapshot: Snapshot = Db::instance().get(&snapshot_key(id))?;
+    import_state(snapshot.take_state())?;
+    rewind_chain(snapshot.get_block_number())?;
+    truncate_logs(snapshot.get_log_count())?;
+    set_snapshot_count(id)
+}
+
+/// Discards all snapshots.
+pub fn discard_snapshots() -> Result<()> {
+    set_snapshot_count(0)
+}
@@ -0,0 +1,470 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use bigint::{Address, H256, M256, U256};
+use rlp::RlpStream;
+use sha3::{Digest, Keccak256};
+
+use error::{EvmError, Result};
+use evm::{parse_address, parse_hex, parse_u256};
+use evm_api::{AccountState, Code, EthState, LegacyEthState, StateRoot, StateVersion,
+              StorageEntry, StorageValue};
+use index::Index;
+use trie::{empty_root, Trie};
+
+use ekiden_core_trusted::db::Db;
+
+// The EVM state is stored with one key per account and one per storage slot, so executing a
+// transaction only reads and writes the entries it touches. The account index lists every
+// address, and each account has an index of its storage slots, so that the whole state can still
+// be enumerated. Indexes are stored one entry per member, so adding an account or slot doesn't
+// rewrite the whole index. `Db` can't delete keys: removed entries are overwritten with an empty
+// message, which reads back as absent.
+//
+// The state is also committed to by a secure Merkle Patricia Trie, as in Ethereum: the state
+// trie maps keccak256(address) to rlp([nonce, balance, storageRoot, codeHash]), and each account
+// has a storage trie mapping keccak256(key) to rlp(value) for its non-zero slots.
+//
+// Code is stored once under its keccak256 hash, and accounts only hold that hash, so contracts
+// with identical code share its storage.
+const STATE_ROOT_KEY: &str = "state_root";
+
+/// Version of the state layout written by this code. Version 0 is the original layout, where the
+/// whole state is a single `EthState` blob under `LEGACY_STATE_KEY`, with numbers stored as
+/// decimal strings and code as hex. Version 1 had no state trie, and version 2 stored the code of
+/// each account inline. Neither can be migrated.
+const STATE_VERSION: u32 = 3;
+
+const STATE_VERSION_KEY: &str = "state_version";
+
+const LEGACY_STATE_KEY: &str = "state";
+
+fn account_key(address: &Address) -> String {
+    format!("account:{}", address.hex())
+}
+
+fn storage_key(address: &Address, index: &U256) -> String {
+    format!("storage:{}:{}", address.hex(), index)
+}
+
+fn account_index() -> Index {
+    Index::new("account_list".to_string())
+}
+
+fn storage_index(address: &Address) -> Index {
+    Index::new(format!("storage_list:{}", address.hex()))
+}
+
+fn code_key(hash: &H256) -> String {
+    format!("code:{}", hash.hex())
+}
+
+/// Encodes a number as 32 big-endian bytes.
+pub fn encode_u256(value: &U256) -> Vec<u8> {
+    let mut bytes = vec![0; 32];
+    value.to_big_endian(&mut bytes);
+    bytes
+}
+
+/// Decodes a number encoded with `encode_u256`.
+pub fn decode_u256(bytes: &[u8]) -> Result<U256> {
+    if bytes.len() != 32 {
+        return Err(EvmError::InvalidEncoding(format!(
+            "expected 32 bytes, got {}",
+            bytes.len()
+        )));
+    }
+    Ok(U256::from(bytes))
+}
+
+/// Converts a nonce to the 64-bit number that transactions and dumps carry.
+pub fn nonce_u64(nonce: &U256) -> Result<u64> {
+    if *nonce > U256::from(u64::max_value()) {
+        return Err(EvmError::InvalidNumber(format!("{}", nonce)));
+    }
+    Ok(nonce.as_u64())
+}
+
+/// Decodes a 32-byte hash.
+pub fn decode_h256(bytes: &[u8]) -> Result<H256> {
+    if bytes.len() != 32 {
+        return Err(EvmError::InvalidEncoding(format!(
+            "expected 32 bytes, got {}",
+            bytes.len()
+        )));
+    }
+    Ok(H256::from(bytes))
+}
+
+/// Decodes a 20-byte address.
+pub fn decode_address(bytes: &[u8]) -> Result<Address> {
+    if bytes.len() != 20 {
+        return Err(EvmError::InvalidEncoding(format!(
+            "expected 20 bytes, got {}",
+            bytes.len()
+        )));
+    }
+    Ok(Address::from(bytes))
+}
+
+/// Changes made to the state by a batch of transactions, to be persisted with `apply_changes`.
+#[derive(Default)]
+pub struct StateChanges {
+    /// New contents of every modified account. Storage is kept in `storage`. Accounts are
+    /// ordered so that every compute node appends new ones to the account index in the same order.
+    pub accounts: BTreeMap<Address, AccountState>,
+    /// Modified storage slots of each account. Accounts whose storage is modified must also
+    /// appear in `accounts`.
+    pub storage: HashMap<Address, BTreeMap<U256, M256>>,
+    /// Code of newly created contracts, keyed by hash.
+    pub code: HashMap<H256, Vec<u8>>,
+    /// Accounts to remove along with their storage.
+    pub removed: HashSet<Address>,
+}
+
+/// Returns the stored account at `address`, if any. Its storage is read with `get_storage`.
+pub fn get_account(address: &Address) -> Option<AccountState> {
+    match Db::instance().get::<AccountState>(&account_key(address)) {
+        Ok(ref account) if account.get_address().is_empty() => None,
+        Ok(account) => Some(account),
+        Err(_) => None,
+    }
+}
+
+/// Returns the value of a storage slot. Slots that were never written hold zero.
+pub fn get_storage(address: &Address, index: &U256) -> Result<M256> {
+    match Db::instance().get::<StorageValue>(&storage_key(address, index)) {
+        Ok(ref value) if !value.get_value().is_empty() => {
+            Ok(M256(decode_u256(value.get_value())?))
+        }
+        _ => Ok(M256::zero()),
+    }
+}
+
+/// Returns the addresses of all stored accounts.
+pub fn account_addresses() -> Result<Vec<Address>> {
+    account_range(0, account_count())
+}
+
+/// Returns the number of stored accounts.
+pub fn account_count() -> u64 {
+    account_index().len()
+}
+
+/// Returns the addresses at positions `start` up to, but excluding, `end` of the account index.
+pub fn account_range(start: u64, end: u64) -> Result<Vec<Address>> {
+    let members = account_index().range(start, end)?;
+
+    let mut addresses = Vec::with_capacity(members.len());
+    for address in &members {
+        addresses.push(decode_address(address)?);
+    }
+    Ok(addresses)
+}
+
+/// Returns every storage slot written for the account at `address`.
+pub fn account_storage(address: &Address) -> Result<BTreeMap<U256, M256>> {
+    let mut storage = BTreeMap::new();
+    for key in storage_index(address).members()? {
+        let key = decode_u256(&key)?;
+        let value = get_storage(address, &key)?;
+        storage.insert(key, value);
+    }
+    Ok(storage)
+}
+
+/// Returns the root of the state trie.
+pub fn state_root() -> Result<H256> {
+    match Db::instance().get::<StateRoot>(STATE_ROOT_KEY) {
+        Ok(ref root) if !root.get_root().is_empty() => decode_h256(root.get_root()),
+        _ => Ok(empty_root()),
+    }
+}
+
+/// Returns the root of the storage trie of `account`.
+pub fn storage_root(account: &AccountState) -> Result<H256> {
+    if account.get_storage_root().is_empty() {
+        Ok(empty_root())
+    } else {
+        decode_h256(account.get_storage_root())
+    }
+}
+
+/// Returns the hash of empty code, i.e. keccak256("").
+pub fn empty_code_hash() -> H256 {
+    H256::from(Keccak256::digest(&[]).as_slice())
+}
+
+/// Returns the hash of the code of `account`.
+pub fn code_hash(account: &AccountState) -> Result<H256> {
+    if account.get_code_hash().is_empty() {
+        Ok(empty_code_hash())
+    } else {
+        decode_h256(account.get_code_hash())
+    }
+}
+
+/// Returns the code with the given hash.
+pub fn get_code(hash: &H256) -> Result<Vec<u8>> {
+    if *hash == empty_code_hash() {
+        return Ok(Vec::new());
+    }
+
+    let mut code: Code = Db::instance().get(&code_key(hash))?;
+    Ok(code.take_code())
+}
+
+/// Moves the code held inline by `account` to `code`, leaving only its hash in the account.
+pub fn move_code(account: &mut AccountState, code: &mut HashMap<H256, Vec<u8>>) {
+    let inline_code = account.take_code();
+    let hash = H256::from(Keccak256::digest(&inline_code).as_slice());
+    account.set_code_hash(hash.to_vec());
+    if !inline_code.is_empty() {
+        code.insert(hash, inline_code);
+    }
+}
+
+/// Encodes an account as stored in the state trie.
+fn account_rlp(account: &AccountState) -> Result<Vec<u8>> {
+    let mut stream = RlpStream::new_list(4);
+    stream.append(&decode_u256(account.get_nonce())?);
+    stream.append(&decode_u256(account.get_balance())?);
+    stream.append(&storage_root(account)?);
+    stream.append(&code_hash(account)?);
+    Ok(stream.out())
+}
+
+/// Writes storage slots of the account at `address` and updates its storage trie, whose new root
+/// is returned.
+fn write_storage(address: &Address, root: H256, slots: BTreeMap<U256, M256>) -> Result<H256> {
+    let mut trie = Trie::open(root);
+
+    let storage_index = storage_index(address);
+    for (index, value) in slots {
+        let key = storage_key(address, &index);
+        let exists = Db::instance()
+            .get::<StorageValue>(&key)
+            .map(|value| !value.get_value().is_empty())
+            .unwrap_or(false);
+
+        let value: U256 = value.into();
+        let trie_key = Keccak256::digest(&encode_u256(&index));
+        if value.is_zero() {
+            // As in Ethereum, zeroed slots are deleted rather than stored.
+            trie.remove(&trie_key)?;
+            if exists {
+                Db::instance().set(&key, StorageValue::new())?;
+                storage_index.remove(&encode_u256(&index))?;
+            }
+        } else {
+            let mut stream = RlpStream::new();
+            stream.append(&value);
+            trie.insert(&trie_key, stream.out())?;
+
+            if !exists {
+                storage_index.insert(&encode_u256(&index))?;
+            }
+            let mut stored = StorageValue::new();
+            stored.set_value(encode_u256(&value));
+            Db::instance().set(&key, stored)?;
+        }
+    }
+
+    trie.commit()
+}
+
+/// Overwrites the account at `address` and all of its storage slots with empty entries. Its code
+/// is kept, since other accounts may share it.
+fn delete_account_entries(address: &Address) -> Result<()> {
+    let slots = account_storage(address)?;
+
+    for index in slots.keys() {
+        Db::instance().set(&storage_key(address, index), StorageValue::new())?;
+    }
+    storage_index(address).clear()?;
+    Db::instance().set(&account_key(address), AccountState::new())?;
+    Ok(())
+}
+
+/// Persists `changes`, only writing the accounts and storage slots they contain, and updates the
+/// state trie. Returns the new state root.
+pub fn apply_changes(changes: StateChanges) -> Result<H256> {
+    let StateChanges {
+        accounts,
+        mut storage,
+        code,
+        removed,
+    } = changes;
+    let mut state_trie = Trie::open(state_root()?);
+
+    for (hash, code) in code {
+        let key = code_key(&hash);
+        if Db::instance().get::<Code>(&key).is_err() {
+            let mut stored = Code::new();
+            stored.set_code(code);
+            Db::instance().set(&key, stored)?;
+        }
+    }
+
+    let account_index = account_index();
+    for (address, mut account) in accounts {
+        let previous = get_account(&address);
+        let mut root = match previous {
+            Some(ref previous) => storage_root(previous)?,
+            None => {
+                account_index.insert(&address)?;
+                empty_root()
+            }
+        };
+        if let Some(slots) = storage.remove(&address) {
+            root = write_storage(&address, root, slots)?;
+        }
+        account.set_storage_root(root.to_vec());
+
+        state_trie.insert(&Keccak256::digest(&address), account_rlp(&account)?)?;
+        Db::instance().set(&account_key(&address), account)?;
+    }
+
+    for address in removed {
+        if get_account(&address).is_none() {
+            continue;
+        }
+        delete_account_entries(&address)?;
+        state_trie.remove(&Keccak256::digest(&address))?;
+        account_index.remove(&address)?;
+    }
+
+    let root = state_trie.commit()?;
+    let mut state_root = StateRoot::new();
+    state_root.set_root(root.to_vec());
+    Db::instance().set(STATE_ROOT_KEY, state_root)?;
+    Ok(root)
+}
+
+fn set_state_version(version: u32) -> Result<()> {
+    let mut state_version = StateVersion::new();
+    state_version.set_version(version);
+    Db::instance().set(STATE_VERSION_KEY, state_version)?;
+    Ok(())
+}
+
+/// Returns the version of the stored state layout.
+pub fn state_version() -> u32 {
+    Db::instance()
+        .get::<StateVersion>(STATE_VERSION_KEY)
+        .map(|version| version.get_version())
+        .unwrap_or(0)
+}
+
+/// Fails if the stored state uses another layout. Older layouts must first be converted with
+/// `migrate`.
+pub fn check_state_version() -> Result<()> {
+    match state_version() {
+        STATE_VERSION => Ok(()),
+        version => Err(EvmError::UnsupportedStateVersion(version)),
+    }
+}
+
+/// Converts the state written by an older version of the contract to the current layout, and
+/// returns the version it was converted from.
+pub fn migrate() -> Result<u32> {
+    let version = state_version();
+    match version {
+        STATE_VERSION => return Ok(version),
+        0 => (),
+        _ => return Err(EvmError::UnsupportedStateVersion(version)),
+    }
+
+    let legacy: LegacyEthState = Db::instance()
+        .get(LEGACY_STATE_KEY)
+        .unwrap_or_else(|_| LegacyEthState::new());
+
+    let mut changes = StateChanges::default();
+    for legacy_account in legacy.get_accounts().values() {
+        let address = parse_address(legacy_account.get_address())?;
+
+        let mut account = AccountState::new();
+        account.set_nonce(encode_u256(&parse_u256(legacy_account.get_nonce())?));
+        account.set_address(address.to_vec());
+        account.set_balance(encode_u256(&parse_u256(legacy_account.get_balance())?));
+        account.set_code(parse_hex(legacy_account.get_code())?);
+        move_code(&mut account, &mut changes.code);
+        changes.accounts.insert(address, account);
+
+        let mut storage = BTreeMap::new();
+        for (key, value) in legacy_account.get_storage() {
+            storage.insert(parse_u256(key)?, M256(parse_u256(value)?));
+        }
+        changes.storage.insert(address, storage);
+    }
+
+    apply_changes(changes)?;
+    Db::instance().set(LEGACY_STATE_KEY, LegacyEthState::new())?;
+    set_state_version(STATE_VERSION)?;
+    Ok(version)
+}
+
+/// Removes every account and storage slot, leaving an empty state in the current layout.
+pub fn clear() -> Result<()> {
+    for address in account_addresses()? {
+        delete_account_entries(&address)?;
+    }
+
+    account_index().clear()?;
+    Db::instance().set(STATE_ROOT_KEY, StateRoot::new())?;
+    Db::instance().set(LEGACY_STATE_KEY, LegacyEthState::new())?;
+    set_state_version(STATE_VERSION)
+}
+
+/// Returns the whole state, including the code and non-zero storage slots of each account.
+pub fn export_state() -> Result<EthState> {
+    let mut state = EthState::new();
+    for address in account_addresses()? {
+        let mut account = match get_account(&address) {
+            Some(account) => account,
+            None => continue,
+        };
+        let code = get_code(&code_hash(&account)?)?;
+        account.set_code(code);
+        account.clear_code_hash();
+
+        for (key, value) in account_storage(&address)? {
+            let value: U256 = value.into();
+            if value.is_zero() {
+                continue;
+            }
+
+            let mut entry = StorageEntry::new();
+            entry.set_key(encode_u256(&key));
+            entry.set_value(encode_u256(&value));
+            account.mut_storage().push(entry);
+        }
+        state.mut_accounts().push(account);
+    }
+    Ok(state)
+}
+
+/// Replaces the whole state with `state`, and returns the new state root.
+pub fn import_state(mut state: EthState) -> Result<H256> {
+    clear()?;
+
+    let mut changes = StateChanges::default();
+    for mut account in state.take_accounts().into_iter() {
+        let address = decode_address(account.get_address())?;
+
+        let mut storage = BTreeMap::new();
+        for entry in account.take_storage().into_iter() {
+            storage.insert(
+                decode_u256(entry.get_key())?,
+                M256(decode_u256(entry.get_value())?),
+            );
+        }
+        // The storage root is recomputed from the slots.
+        account.clear_storage_root();
+        move_code(&mut account, &mut changes.code);
+
+        changes.accounts.insert(address, account);
+        changes.storage.insert(address, storage);
+    }
+
+    apply_changes(changes)
+}
@@ -0,0 +1,447 @@
+use std::rc::Rc;
+
+use bigint::{Address, Gas, H256, U256};
+use hexutil::to_hex;
+use rlp::{DecoderError, RlpStream, UntrustedRlp};
+use secp256k1::{recover, Message, RecoveryId, Signature};
+use sha3::{Digest, Keccak256};
+use sputnikvm::{TransactionAction, ValidTransaction};
+
+use block::transaction_in_chain;
+use error::{EvmError, Result};
+use evm::account_nonce;
+use evm_api::Transaction;
+use state::nonce_u64;
+
+use ekiden_core_trusted::db::Db;
+
+/// Order of the secp256k1 curve, big-endian.
+const SECP256K1_N: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+
+fn transaction_key(hash: &H256) -> String {
+    format!("transaction:{}", to_hex(hash))
+}
+
+/// Appends the fields of `transaction` that a signature covers to `stream`: nonce, gasPrice,
+/// gasLimit, to, value and data.
+fn append_fields(stream: &mut RlpStream, transaction: &ValidTransaction) {
+    stream.append(&transaction.nonce);
+    stream.append(&transaction.gas_price);
+    stream.append(&transaction.gas_limit);
+    match transaction.action {
+        TransactionAction::Call(address) => stream.append(&address),
+        TransactionAction::Create => stream.append_empty_data(),
+    };
+    stream.append(&transaction.value);
+    stream.append(&*transaction.input);
+}
+
+/// Computes the hash a signed transaction is known by to wallets and block explorers:
+/// keccak256(rlp([nonce, gasPrice, gasLimit, to, value, data, v, r, s])), with every item in its
+/// canonical encoding.
+fn signed_transaction_hash(transaction: &ValidTransaction, v: &U256, r: &U256, s: &U256) -> H256 {
+    let mut stream = RlpStream::new_list(9);
+    append_fields(&mut stream, transaction);
+    stream.append(v);
+    stream.append(r);
+    stream.append(s);
+
+    H256::from(Keccak256::digest(&stream.out()).as_slice())
+}
+
+/// Computes the hash identifying an unsigned transaction, i.e. one the enclave builds from a
+/// request that doesn't carry a transaction signature, like create and transfer, which sign a
+/// request hash of their own instead. Such a transaction has no v, r and s, so the caller is
+/// hashed in their place: keccak256(rlp([nonce, gasPrice, gasLimit, to, value, data, caller])).
+/// Its seven items keep it from colliding with the hash of a signed transaction.
+fn transaction_hash(transaction: &ValidTransaction) -> H256 {
+    let mut stream = RlpStream::new_list(7);
+    append_fields(&mut stream, transaction);
+    stream.append(&transaction.caller.unwrap_or_default());
+
+    H256::from(Keccak256::digest(&stream.out()).as_slice())
+}
+
+/// Computes the hash a wallet signs to send `transaction` on chain `chain_id`, as defined by
+/// EIP-155: keccak256(rlp([nonce, gasPrice, gasLimit, to, value, data, chainId, 0, 0])).
+fn signing_hash(transaction: &ValidTransaction, chain_id: u64) -> H256 {
+    let mut stream = RlpStream::new_list(9);
+    append_fields(&mut stream, transaction);
+    stream.append(&U256::from(chain_id));
+    stream.append_empty_data();
+    stream.append_empty_data();
+
+    H256::from(Keccak256::digest(&stream.out()).as_slice())
+}
+
+/// A transaction to execute, along with the hash identifying it.
+pub struct HashedTransaction {
+    pub hash: H256,
+    pub transaction: ValidTransaction,
+}
+
+impl HashedTransaction {
+    /// Identifies an unsigned transaction by `transaction_hash`.
+    pub fn unsigned(transaction: ValidTransaction) -> Self {
+        HashedTransaction {
+            hash: transaction_hash(&transaction),
+            transaction: transaction,
+        }
+    }
+
+    /// Checks that `signature` is the EIP-155 signature of `transaction` by its caller for chain
+    /// `chain_id`, and identifies the transaction by the hash of the signed transaction, i.e. the
+    /// hash `send_raw_transaction` reports for the same transaction and signature.
+    pub fn signed(transaction: ValidTransaction, chain_id: u64, signature: &[u8]) -> Result<Self> {
+        check_transaction_signature(&transaction, chain_id, signature)?;
+
+        // Since EIP-155, v = chainId * 2 + 35 + recoveryId.
+        let (r, s, recovery_id) = split_signature(signature)?;
+        let v = U256::from(chain_id) * U256::from(2) + U256::from(35 + recovery_id);
+        Ok(HashedTransaction {
+            hash: signed_transaction_hash(&transaction, &v, &r, &s),
+            transaction: transaction,
+        })
+    }
+}
+
+/// A transaction decoded from its signed RLP encoding, as submitted by wallets.
+pub struct SignedTransaction {
+    /// Hash wallets and block explorers know the transaction by. See `signed_transaction_hash`.
+    pub hash: H256,
+    /// The transaction, whose caller is `sender`.
+    pub transaction: ValidTransaction,
+    /// Sender recovered from the signature.
+    pub sender: Address,
+    /// Chain id the transaction was signed for, or `None` if it isn't replay-protected by
+    /// EIP-155.
+    pub chain_id: Option<u64>,
+}
+
+/// Recovers the address whose key signed `hash` with signature (r, s) and the given recovery id.
+/// The signature must be canonical, with s in the lower half of the curve order as required since
+/// Homestead (EIP-2).
+fn recover_address(hash: &H256, r: &U256, s: &U256, recovery_id: u8) -> Result<Address> {
+    let n = U256::from(&SECP256K1_N[..]);
+    if r.is_zero() || *r >= n || s.is_zero() || *s > n / U256::from(2) {
+        return Err(EvmError::InvalidSignature);
+    }
+
+    let mut message = [0u8; 32];
+    message.copy_from_slice(hash);
+    let mut signature = [0u8; 64];
+    r.to_big_endian(&mut signature[..32]);
+    s.to_big_endian(&mut signature[32..]);
+    let recovery_id = RecoveryId::parse(recovery_id).map_err(|_| EvmError::InvalidSignature)?;
+    let public_key = recover(
+        &Message::parse(&message),
+        &Signature::parse(&signature),
+        &recovery_id,
+    ).map_err(|_| EvmError::InvalidSignature)?;
+
+    // The address is the last 20 bytes of the keccak256 hash of the uncompressed public key,
+    // without its 0x04 prefix.
+    let public_key = public_key.serialize();
+    Ok(Address::from(&Keccak256::digest(&public_key[1..])[12..]))
+}
+
+/// Splits a 65-byte signature r || s || v into r, s and the recovery id. v is the recovery id,
+/// either as 0 or 1 or as 27 or 28.
+fn split_signature(signature: &[u8]) -> Result<(U256, U256, u8)> {
+    if signature.len() != 65 {
+        return Err(EvmError::InvalidSignature);
+    }
+    let recovery_id = match signature[64] {
+        v @ 0...1 => v,
+        v @ 27...28 => v - 27,
+        _ => return Err(EvmError::InvalidSignature),
+    };
+    let r = U256::from(&signature[..32]);
+    let s = U256::from(&signature[32..64]);
+    Ok((r, s, recovery_id))
+}
+
+/// Recovers the signer of `hash` from a 65-byte signature r || s || v. See `split_signature`.
+fn recover_signer(hash: &H256, signature: &[u8]) -> Result<Address> {
+    let (r, s, recovery_id) = split_signature(signature)?;
+    recover_address(hash, &r, &s, recovery_id)
+}
+
+/// Computes the hash a token holder signs to authorize a transfer of `amount` tokens of
+/// `contract` to `to`: keccak256(contract || to || amount || nonce || gasLimit || gasPrice ||
+/// chainId), with the numbers encoded as 32-byte big-endian words. `nonce` is the holder's account
+/// nonce, so a signature can't be replayed once the transfer has executed, nor on another chain.
+/// The gas price is signed since the holder pays the fee.
+pub fn transfer_hash(
+    contract: &Address,
+    to: &Address,
+    amount: &U256,
+    nonce: &U256,
+    gas_limit: Gas,
+    gas_price: Gas,
+    chain_id: u64,
+) -> H256 {
+    let gas_limit: U256 = gas_limit.into();
+    let gas_price: U256 = gas_price.into();
+
+    let mut message = Vec::with_capacity(20 + 20 + 5 * 32);
+    message.extend_from_slice(contract);
+    message.extend_from_slice(to);
+    message.extend_from_slice(&H256::from(*amount));
+    message.extend_from_slice(&H256::from(*nonce));
+    message.extend_from_slice(&H256::from(gas_limit));
+    message.extend_from_slice(&H256::from(gas_price));
+    message.extend_from_slice(&H256::from(U256::from(chain_id)));
+
+    H256::from(Keccak256::digest(&message).as_slice())
+}
+
+/// Computes the hash a creator signs to create a token with `initial_supply` tokens:
+/// keccak256(initialSupply || nonce || gasLimit || gasPrice || chainId), encoded as in
+/// `transfer_hash`.
+pub fn create_token_hash(
+    initial_supply: &U256,
+    nonce: &U256,
+    gas_limit: Gas,
+    gas_price: Gas,
+    chain_id: u64,
+) -> H256 {
+    let gas_limit: U256 = gas_limit.into();
+    let gas_price: U256 = gas_price.into();
+
+    let mut message = Vec::with_capacity(5 * 32);
+    message.extend_from_slice(&H256::from(*initial_supply));
+    message.extend_from_slice(&H256::from(*nonce));
+    message.extend_from_slice(&H256::from(gas_limit));
+    message.extend_from_slice(&H256::from(gas_price));
+    message.extend_from_slice(&H256::from(U256::from(chain_id)));
+
+    H256::from(Keccak256::digest(&message).as_slice())
+}
+
+fn malformed(_error: DecoderError) -> EvmError {
+    EvmError::InvalidTransaction("malformed RLP".to_string())
+}
+
+/// Decodes rlp([nonce, gasPrice, gasLimit, to, value, data, v, r, s]) and recovers its sender.
+pub fn decode_signed_transaction(raw: &[u8]) -> Result<SignedTransaction> {
+    let rlp = UntrustedRlp::new(raw);
+    if !rlp.is_list() || rlp.iter().count() != 9 {
+        return Err(EvmError::InvalidTransaction("expected a list of 9 items".to_string()));
+    }
+
+    let nonce: U256 = rlp.val_at(0).map_err(malformed)?;
+    let gas_price: U256 = rlp.val_at(1).map_err(malformed)?;
+    let gas_limit: U256 = rlp.val_at(2).map_err(malformed)?;
+    let to = rlp.at(3).map_err(malformed)?;
+    let action = if to.is_empty() {
+        TransactionAction::Create
+    } else {
+        TransactionAction::Call(to.as_val::<Address>().map_err(malformed)?)
+    };
+    let value: U256 = rlp.val_at(4).map_err(malformed)?;
+    let input = rlp.at(5).and_then(|data| data.data()).map_err(malformed)?;
+    let v: U256 = rlp.val_at(6).map_err(malformed)?;
+    let r: U256 = rlp.val_at(7).map_err(malformed)?;
+    let s: U256 = rlp.val_at(8).map_err(malformed)?;
+
+    // Since EIP-155, v = chainId * 2 + 35 + recoveryId. Unprotected signatures have
+    // v = 27 + recoveryId.
+    if v > U256::from(u64::max_value()) {
+        return Err(EvmError::InvalidTransaction("invalid v".to_string()));
+    }
+    let v = v.as_u64();
+    let (chain_id, recovery_id) = match v {
+        27 | 28 => (None, v - 27),
+        v if v >= 35 => (Some((v - 35) / 2), (v - 35) % 2),
+        _ => return Err(EvmError::InvalidTransaction("invalid v".to_string())),
+    };
+
+    // The signature covers the first six items, followed for EIP-155 by [chainId, 0, 0].
+    let mut stream = match chain_id {
+        Some(_) => RlpStream::new_list(9),
+        None => RlpStream::new_list(6),
+    };
+    for i in 0..6 {
+        stream.append_raw(rlp.at(i).map_err(malformed)?.as_raw(), 1);
+    }
+    if let Some(chain_id) = chain_id {
+        stream.append(&U256::from(chain_id));
+        stream.append_empty_data();
+        stream.append_empty_data();
+    }
+    let signing_hash = H256::from(Keccak256::digest(&stream.out()).as_slice());
+    let sender = recover_address(&signing_hash, &r, &s, recovery_id as u8)?;
+
+    let transaction = ValidTransaction {
+        caller: Some(sender),
+        action: action,
+        gas_price: Gas::from(gas_price),
+        gas_limit: Gas::from(gas_limit),
+        value: value,
+        input: Rc::new(input.to_vec()),
+        nonce: nonce,
+    };
+    Ok(SignedTransaction {
+        hash: signed_transaction_hash(&transaction, &U256::from(v), &r, &s),
+        transaction: transaction,
+        sender: sender,
+        chain_id: chain_id,
+    })
+}
+
+/// Checks that `signed` is replay-protected for chain `chain_id`, and that its nonce is the next
+/// nonce of its sender.
+pub fn check_signed_transaction(signed: &SignedTransaction, chain_id: u64) -> Result<()> {
+    if signed.chain_id != Some(chain_id) {
+        return Err(EvmError::InvalidChainId(signed.chain_id));
+    }
+    check_nonce(&signed.sender, &signed.transaction.nonce)
+}
+
+/// Checks that `nonce` is the next nonce of `address`, so that a signature covering it can't be
+/// replayed.
+pub fn check_nonce(address: &Address, nonce: &U256) -> Result<()> {
+    if *nonce != account_nonce(address)? {
+        return Err(EvmError::InvalidNonce(*address));
+    }
+    Ok(())
+}
+
+/// Checks that `signature` is by the key of `from`, over `hash`.
+pub fn check_request_signature(from: &Address, hash: &H256, signature: &[u8]) -> Result<()> {
+    if recover_signer(hash, signature)? != *from {
+        return Err(EvmError::UnauthorizedSender(*from));
+    }
+    Ok(())
+}
+
+/// Checks that `signature` is by the key of the caller of `transaction`, over its EIP-155 signing
+/// hash for chain `chain_id`. That is, the caller signed exactly what it would have sent with
+/// `send_raw_transaction`.
+fn check_transaction_signature(
+    transaction: &ValidTransaction,
+    chain_id: u64,
+    signature: &[u8],
+) -> Result<()> {
+    let caller = transaction.caller.unwrap_or_default();
+    check_request_signature(&caller, &signing_hash(transaction, chain_id), signature)
+}
+
+/// Persists the transactions executed in block `block_number`, in execution order, keyed by
+/// hash.
+pub fn store_transactions(transactions: &[HashedTransaction], block_number: u64) -> Result<()> {
+    let mut db = Db::instance();
+    for (index, hashed) in transactions.iter().enumerate() {
+        let t = &hashed.transaction;
+
+        let mut transaction = Transaction::new();
+        transaction.set_hash(to_hex(&hashed.hash));
+        transaction.set_block_number(block_number);
+        transaction.set_transaction_index(index as u64);
+        transaction.set_from(to_hex(&t.caller.unwrap_or_default()));
+        if let TransactionAction::Call(address) = t.action {
+            transaction.set_to(to_hex(&address));
+        }
+        transaction.set_nonce(nonce_u64(&t.nonce)?);
+        transaction.set_value(format!("{}", t.value));
+        let gas_price: U256 = t.gas_price.into();
+        transaction.set_gas_price(format!("{}", gas_price));
+        transaction.set_gas(t.gas_limit.as_u64());
+        transaction.set_input(t.input.to_vec());
+        db.set(&transaction_key(&hashed.hash), transaction)?;
+    }
+    Ok(())
+}
+
+/// Looks up a previously executed transaction.
+pub fn get_transaction(hash: &H256) -> Result<Transaction> {
+    let transaction: Transaction = Db::instance()
+        .get(&transaction_key(hash))
+        .map_err(|_| EvmError::TransactionNotFound(*hash))?;
+
+    if !transaction_in_chain(transaction.get_block_number(), transaction.get_hash()) {
+        return Err(EvmError::TransactionNotFound(*hash));
+    }
+    Ok(transaction)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bigint::{Address, Gas, H256, U256};
+    use hexutil::read_hex;
+    use sha3::{Digest, Keccak256};
+    use sputnikvm::TransactionAction;
+
+    use super::{check_transaction_signature, decode_signed_transaction, HashedTransaction};
+
+    // Example transaction from the EIP-155 specification.
+    const EIP155_EXAMPLE: &str =
+        "0xf86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400\
+         008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d89\
+         97f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83";
+
+    #[test]
+    fn test_decode_eip155_example() {
+        let raw = read_hex(EIP155_EXAMPLE).unwrap();
+        let signed = decode_signed_transaction(&raw).unwrap();
+
+        assert_eq!(
+            signed.sender,
+            Address::from_str("0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f").unwrap()
+        );
+        assert_eq!(signed.chain_id, Some(1));
+        assert_eq!(
+            signed.hash,
+            H256::from_str("0x33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788")
+                .unwrap()
+        );
+
+        let transaction = signed.transaction;
+        assert_eq!(transaction.caller, Some(signed.sender));
+        assert_eq!(transaction.nonce, U256::from(9));
+        assert_eq!(transaction.gas_price, Gas::from(20_000_000_000u64));
+        assert_eq!(transaction.gas_limit, Gas::from(21_000u64));
+        assert_eq!(
+            transaction.value,
+            U256::from_dec_str("1000000000000000000").unwrap()
+        );
+        assert!(transaction.input.is_empty());
+        match transaction.action {
+            TransactionAction::Call(to) => assert_eq!(
+                to,
+                Address::from_str("0x3535353535353535353535353535353535353535").unwrap()
+            ),
+            TransactionAction::Create => panic!("expected a call"),
+        }
+    }
+
+    // A signed request carries the signature of the transaction it executes, as r || s || v.
+    #[test]
+    fn test_transaction_signature() {
+        let raw = read_hex(EIP155_EXAMPLE).unwrap();
+        let transaction = decode_signed_transaction(&raw).unwrap().transaction;
+
+        // r and s end the encoding, as 32-byte strings each with a one-byte prefix. v is 37, which
+        // is 1 * 2 + 35 for chain id 1, so the recovery id is 0.
+        let mut signature = raw[raw.len() - 65..raw.len() - 33].to_vec();
+        signature.extend_from_slice(&raw[raw.len() - 32..]);
+        signature.push(0);
+        assert!(check_transaction_signature(&transaction, 1, &signature).is_ok());
+
+        // The transaction is known by the same hash as when sent with send_raw_transaction.
+        let hashed = HashedTransaction::signed(transaction.clone(), 1, &signature).unwrap();
+        assert_eq!(hashed.hash, H256::from(Keccak256::digest(&raw).as_slice()));
+
+        // The signature commits to the chain id, and to every field of the transaction.
+        assert!(check_transaction_signature(&transaction, 2, &signature).is_err());
+        let mut transaction = transaction;
+        transaction.gas_price = Gas::from(1u64);
+        assert!(check_transaction_signature(&transaction, 1, &signature).is_err());
+    }
+}
@@ -0,0 +1,560 @@
+use std::mem;
+
+use bigint::H256;
+use rlp::{Rlp, RlpStream};
+use sha3::{Digest, Keccak256};
+
+use error::{EvmError, Result};
+use evm_api::TrieNode;
+
+use ekiden_core_trusted::db::Db;
+
+// A Merkle Patricia Trie, as specified in appendix D of the Ethereum Yellow Paper. Nodes whose
+// encoding is at least 32 bytes long are stored under their hash, and are only loaded when an
+// update reaches them. Smaller nodes are embedded in their parent. Nodes are never removed, so
+// every root that was ever committed remains readable.
+
+fn node_key(hash: &H256) -> String {
+    format!("trie_node:{}", hash.hex())
+}
+
+/// Storage for the encoded nodes of tries, keyed by their hash.
+pub trait NodeStore {
+    fn get(&self, hash: &H256) -> Result<Vec<u8>>;
+
+    fn set(&self, hash: H256, encoded: Vec<u8>) -> Result<()>;
+}
+
+/// Stores trie nodes in `Db`.
+pub struct DbNodeStore;
+
+impl NodeStore for DbNodeStore {
+    fn get(&self, hash: &H256) -> Result<Vec<u8>> {
+        let mut node: TrieNode = Db::instance().get(&node_key(hash))?;
+        Ok(node.take_rlp())
+    }
+
+    fn set(&self, hash: H256, encoded: Vec<u8>) -> Result<()> {
+        let mut node = TrieNode::new();
+        node.set_rlp(encoded);
+        Db::instance().set(&node_key(&hash), node)?;
+        Ok(())
+    }
+}
+
+/// Returns the root hash of an empty trie, i.e. keccak256(rlp("")).
+pub fn empty_root() -> H256 {
+    H256::from(Keccak256::digest(&[0x80]).as_slice())
+}
+
+enum Node {
+    Empty,
+    /// Remaining path nibbles and value.
+    Leaf(Vec<u8>, Vec<u8>),
+    /// Shared path nibbles and the node they lead to.
+    Extension(Vec<u8>, Box<Node>),
+    /// One child per nibble, and the value of the key ending here.
+    Branch(Vec<Node>, Option<Vec<u8>>),
+    /// A stored node that hasn't been loaded yet.
+    Stored(H256),
+}
+
+fn to_nibbles(key: &[u8]) -> Vec<u8> {
+    let mut nibbles = Vec::with_capacity(key.len() * 2);
+    for byte in key {
+        nibbles.push(byte >> 4);
+        nibbles.push(byte & 0x0f);
+    }
+    nibbles
+}
+
+/// Hex-prefix encoding of a path, which also records whether it belongs to a leaf.
+fn encode_path(nibbles: &[u8], leaf: bool) -> Vec<u8> {
+    let flag = if leaf { 2 } else { 0 };
+    let mut bytes = Vec::with_capacity(nibbles.len() / 2 + 1);
+    let rest = if nibbles.len() % 2 == 1 {
+        bytes.push((flag + 1) << 4 | nibbles[0]);
+        &nibbles[1..]
+    } else {
+        bytes.push(flag << 4);
+        nibbles
+    };
+    for pair in rest.chunks(2) {
+        bytes.push(pair[0] << 4 | pair[1]);
+    }
+    bytes
+}
+
+fn decode_path(bytes: &[u8]) -> Result<(Vec<u8>, bool)> {
+    if bytes.is_empty() {
+        return Err(EvmError::InvalidEncoding("empty trie node path".to_string()));
+    }
+
+    let flag = bytes[0] >> 4;
+    let mut nibbles = Vec::with_capacity(bytes.len() * 2);
+    if flag & 1 == 1 {
+        nibbles.push(bytes[0] & 0x0f);
+    }
+    nibbles.extend(to_nibbles(&bytes[1..]));
+    Ok((nibbles, flag & 2 == 2))
+}
+
+fn decode_node(rlp: &Rlp) -> Result<Node> {
+    if rlp.is_data() && rlp.is_empty() {
+        return Ok(Node::Empty);
+    }
+
+    match rlp.item_count() {
+        2 => {
+            let (path, leaf) = decode_path(rlp.at(0).data())?;
+            if leaf {
+                Ok(Node::Leaf(path, rlp.at(1).data().to_vec()))
+            } else {
+                Ok(Node::Extension(path, Box::new(decode_child(&rlp.at(1))?)))
+            }
+        }
+        17 => {
+            let mut children = Vec::with_capacity(16);
+            for index in 0..16 {
+                children.push(decode_child(&rlp.at(index))?);
+            }
+            let value = rlp.at(16).data();
+            let value = if value.is_empty() {
+                None
+            } else {
+                Some(value.to_vec())
+            };
+            Ok(Node::Branch(children, value))
+        }
+        _ => Err(EvmError::InvalidEncoding("malformed trie node".to_string())),
+    }
+}
+
+/// Decodes a reference to a child node, which is either its hash or the node itself.
+fn decode_child(rlp: &Rlp) -> Result<Node> {
+    if rlp.is_list() {
+        decode_node(rlp)
+    } else if rlp.is_empty() {
+        Ok(Node::Empty)
+    } else {
+        Ok(Node::Stored(H256::from(rlp.data())))
+    }
+}
+
+fn load<S: NodeStore>(store: &S, hash: H256) -> Result<Node> {
+    if hash == empty_root() {
+        return Ok(Node::Empty);
+    }
+
+    decode_node(&Rlp::new(&store.get(&hash)?))
+}
+
+fn resolve<S: NodeStore>(store: &S, node: Node) -> Result<Node> {
+    match node {
+        Node::Stored(hash) => load(store, hash),
+        node => Ok(node),
+    }
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|&(x, y)| x == y).count()
+}
+
+fn empty_branch() -> Node {
+    Node::Branch((0..16).map(|_| Node::Empty).collect(), None)
+}
+
+/// Places `node` below an extension with the given path, merging it into the node's own path
+/// where possible.
+fn join<S: NodeStore>(store: &S, prefix: Vec<u8>, node: Node) -> Result<Node> {
+    if prefix.is_empty() {
+        return Ok(node);
+    }
+
+    Ok(match resolve(store, node)? {
+        Node::Empty => Node::Empty,
+        Node::Leaf(path, value) => Node::Leaf([prefix, path].concat(), value),
+        Node::Extension(path, child) => Node::Extension([prefix, path].concat(), child),
+        node => Node::Extension(prefix, Box::new(node)),
+    })
+}
+
+fn insert<S: NodeStore>(store: &S, node: Node, path: &[u8], value: Vec<u8>) -> Result<Node> {
+    Ok(match resolve(store, node)? {
+        Node::Empty => Node::Leaf(path.to_vec(), value),
+        Node::Leaf(leaf_path, leaf_value) => {
+            if leaf_path == path {
+                Node::Leaf(leaf_path, value)
+            } else {
+                // Split the leaf at the first differing nibble.
+                let common = common_prefix(&leaf_path, path);
+                let branch = insert(store, empty_branch(), &leaf_path[common..], leaf_value)?;
+                let branch = insert(store, branch, &path[common..], value)?;
+                join(store, path[..common].to_vec(), branch)?
+            }
+        }
+        Node::Extension(extension_path, child) => {
+            let common = common_prefix(&extension_path, path);
+            if common == extension_path.len() {
+                let child = insert(store, *child, &path[common..], value)?;
+                Node::Extension(extension_path, Box::new(child))
+            } else {
+                // Split the extension at the first differing nibble.
+                let mut children: Vec<Node> = (0..16).map(|_| Node::Empty).collect();
+                children[extension_path[common] as usize] =
+                    join(store, extension_path[common + 1..].to_vec(), *child)?;
+                let branch = insert(store, Node::Branch(children, None), &path[common..], value)?;
+                join(store, path[..common].to_vec(), branch)?
+            }
+        }
+        Node::Branch(mut children, branch_value) => {
+            if path.is_empty() {
+                Node::Branch(children, Some(value))
+            } else {
+                let index = path[0] as usize;
+                let child = mem::replace(&mut children[index], Node::Empty);
+                children[index] = insert(store, child, &path[1..], value)?;
+                Node::Branch(children, branch_value)
+            }
+        }
+        Node::Stored(_) => unreachable!(),
+    })
+}
+
+/// Collapses a branch left with fewer than two entries after a removal.
+fn normalize_branch<S: NodeStore>(
+    store: &S,
+    children: Vec<Node>,
+    value: Option<Vec<u8>>,
+) -> Result<Node> {
+    let used: Vec<usize> = children
+        .iter()
+        .enumerate()
+        .filter(|&(_, child)| match *child {
+            Node::Empty => false,
+            _ => true,
+        })
+        .map(|(index, _)| index)
+        .collect();
+
+    match (used.len(), value) {
+        (0, None) => Ok(Node::Empty),
+        (0, Some(value)) => Ok(Node::Leaf(Vec::new(), value)),
+        (1, None) => {
+            let index = used[0];
+            let child = children.into_iter().nth(index).unwrap();
+            join(store, vec![index as u8], child)
+        }
+        (_, value) => Ok(Node::Branch(children, value)),
+    }
+}
+
+fn remove<S: NodeStore>(store: &S, node: Node, path: &[u8]) -> Result<Node> {
+    Ok(match resolve(store, node)? {
+        Node::Empty => Node::Empty,
+        Node::Leaf(leaf_path, value) => {
+            if leaf_path == path {
+                Node::Empty
+            } else {
+                Node::Leaf(leaf_path, value)
+            }
+        }
+        Node::Extension(extension_path, child) => {
+            if path.starts_with(&extension_path) {
+                let child = remove(store, *child, &path[extension_path.len()..])?;
+                join(store, extension_path, child)?
+            } else {
+                Node::Extension(extension_path, child)
+            }
+        }
+        Node::Branch(mut children, value) => {
+            if path.is_empty() {
+                normalize_branch(store, children, None)?
+            } else {
+                let index = path[0] as usize;
+                let child = mem::replace(&mut children[index], Node::Empty);
+                children[index] = remove(store, child, &path[1..])?;
+                normalize_branch(store, children, value)?
+            }
+        }
+        Node::Stored(_) => unreachable!(),
+    })
+}
+
+fn store_node<S: NodeStore>(store: &S, encoded: Vec<u8>) -> Result<H256> {
+    let hash = H256::from(Keccak256::digest(&encoded).as_slice());
+    store.set(hash, encoded)?;
+    Ok(hash)
+}
+
+/// RLP-encodes `node`, storing any descendants that can't be embedded in it.
+fn encode<S: NodeStore>(store: &S, node: &Node) -> Result<Vec<u8>> {
+    let mut stream;
+    match *node {
+        Node::Empty => {
+            stream = RlpStream::new();
+            stream.append_empty_data();
+        }
+        Node::Leaf(ref path, ref value) => {
+            stream = RlpStream::new_list(2);
+            stream.append(&encode_path(path, true));
+            stream.append(value);
+        }
+        Node::Extension(ref path, ref child) => {
+            stream = RlpStream::new_list(2);
+            stream.append(&encode_path(path, false));
+            append_child(store, &mut stream, child)?;
+        }
+        Node::Branch(ref children, ref value) => {
+            stream = RlpStream::new_list(17);
+            for child in children {
+                append_child(store, &mut stream, child)?;
+            }
+            match *value {
+                Some(ref value) => stream.append(value),
+                None => stream.append_empty_data(),
+            };
+        }
+        Node::Stored(_) => unreachable!(),
+    }
+    Ok(stream.out())
+}
+
+fn append_child<S: NodeStore>(store: &S, stream: &mut RlpStream, child: &Node) -> Result<()> {
+    match *child {
+        Node::Empty => {
+            stream.append_empty_data();
+        }
+        Node::Stored(ref hash) => {
+            stream.append(hash);
+        }
+        ref node => {
+            let encoded = encode(store, node)?;
+            if encoded.len() < 32 {
+                stream.append_raw(&encoded, 1);
+            } else {
+                stream.append(&store_node(store, encoded)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Looks up `key` in the trie with the given root. Returns the value, if any, along with a proof
+/// made of the encodings of the stored nodes on the path to the key, starting with the root.
+/// When the key is absent, the proof shows where its path ends.
+pub fn prove(root: H256, key: &[u8]) -> Result<(Option<Vec<u8>>, Vec<Vec<u8>>)> {
+    let mut proof = Vec::new();
+    let nibbles = to_nibbles(key);
+    let mut path = &nibbles[..];
+
+    let mut node = Node::Stored(root);
+    loop {
+        node = match node {
+            Node::Empty => return Ok((None, proof)),
+            Node::Leaf(leaf_path, value) => {
+                let value = if leaf_path.as_slice() == path {
+                    Some(value)
+                } else {
+                    None
+                };
+                return Ok((value, proof));
+            }
+            Node::Extension(extension_path, child) => {
+                if !path.starts_with(&extension_path) {
+                    return Ok((None, proof));
+                }
+                path = &path[extension_path.len()..];
+                *child
+            }
+            Node::Branch(children, value) => {
+                if path.is_empty() {
+                    return Ok((value, proof));
+                }
+                let index = path[0] as usize;
+                path = &path[1..];
+                children.into_iter().nth(index).unwrap()
+            }
+            Node::Stored(hash) => {
+                if hash == empty_root() {
+                    return Ok((None, proof));
+                }
+                let encoded = DbNodeStore.get(&hash)?;
+                let node = decode_node(&Rlp::new(&encoded))?;
+                proof.push(encoded);
+                node
+            }
+        }
+    }
+}
+
+/// A trie being updated. Changes are only written to its node store by `commit`.
+pub struct Trie<S: NodeStore = DbNodeStore> {
+    root: Node,
+    store: S,
+}
+
+impl Trie {
+    /// Opens the trie with the given root hash, whose nodes are in `Db`.
+    pub fn open(root: H256) -> Self {
+        Trie::with_store(root, DbNodeStore)
+    }
+}
+
+impl<S: NodeStore> Trie<S> {
+    /// Opens the trie with the given root hash, whose nodes are in `store`.
+    pub fn with_store(root: H256, store: S) -> Self {
+        Trie {
+            root: Node::Stored(root),
+            store: store,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let root = mem::replace(&mut self.root, Node::Empty);
+        self.root = insert(&self.store, root, &to_nibbles(key), value)?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        let root = mem::replace(&mut self.root, Node::Empty);
+        self.root = remove(&self.store, root, &to_nibbles(key))?;
+        Ok(())
+    }
+
+    /// Stores the updated nodes and returns the new root hash. The root node is always stored,
+    /// even if its encoding is shorter than 32 bytes.
+    pub fn commit(self) -> Result<H256> {
+        match self.root {
+            Node::Empty => Ok(empty_root()),
+            Node::Stored(hash) => Ok(hash),
+            ref node => store_node(&self.store, encode(&self.store, node)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use bigint::H256;
+    use hexutil::read_hex;
+
+    use error::Result;
+
+    use super::{empty_root, NodeStore, Trie};
+
+    #[derive(Default)]
+    struct MemoryNodeStore {
+        nodes: RefCell<HashMap<H256, Vec<u8>>>,
+    }
+
+    impl<'a> NodeStore for &'a MemoryNodeStore {
+        fn get(&self, hash: &H256) -> Result<Vec<u8>> {
+            Ok(self.nodes.borrow()[hash].clone())
+        }
+
+        fn set(&self, hash: H256, encoded: Vec<u8>) -> Result<()> {
+            self.nodes.borrow_mut().insert(hash, encoded);
+            Ok(())
+        }
+    }
+
+    /// Keys and values starting with "0x" are hex, others are ASCII.
+    fn bytes(value: &str) -> Vec<u8> {
+        if value.starts_with("0x") {
+            read_hex(value).unwrap()
+        } else {
+            value.as_bytes().to_vec()
+        }
+    }
+
+    fn root_of(entries: &[(&str, &str)]) -> H256 {
+        let store = MemoryNodeStore::default();
+        let mut trie = Trie::with_store(empty_root(), &store);
+        for &(key, value) in entries {
+            trie.insert(&bytes(key), bytes(value)).unwrap();
+        }
+        trie.commit().unwrap()
+    }
+
+    // Vectors from trieanyorder.json in the Ethereum tests repository.
+    #[test]
+    fn test_vectors() {
+        let vectors: Vec<(Vec<(&str, &str)>, &str)> = vec![
+            (
+                vec![],
+                "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+            ),
+            (
+                vec![("doe", "reindeer"), ("dog", "puppy"), ("dogglesworth", "cat")],
+                "0x8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3",
+            ),
+            (
+                vec![("do", "verb"), ("horse", "stallion"), ("doge", "coin"), ("dog", "puppy")],
+                "0x5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84",
+            ),
+            (
+                vec![("foo", "bar"), ("food", "bass")],
+                "0x17beaa1648bafa633cda809c90c04af50fc8aed3cb40d16efbddee6fdf63c4c3",
+            ),
+            (
+                vec![("be", "e"), ("dog", "puppy"), ("bed", "d")],
+                "0x3f67c7a47520f79faa29255d2d3c084a7a6df0453116ed7232ff10277a8be68b",
+            ),
+            (
+                vec![("test", "test"), ("te", "testy")],
+                "0x8452568af70d8d140f58d941338542f645fcca50094b20f3c3d8c3df49337928",
+            ),
+            (
+                vec![("0x0045", "0x0123456789"), ("0x4500", "0x9876543210")],
+                "0x285505fcabe84badc8aa310e2aae17eddc7d120aabec8a476902c8184b3a3503",
+            ),
+        ];
+
+        for (entries, root) in vectors {
+            assert_eq!(root_of(&entries), H256::from_str(root).unwrap());
+
+            let mut reversed = entries.clone();
+            reversed.reverse();
+            assert_eq!(root_of(&reversed), H256::from_str(root).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_update_value() {
+        let updated = root_of(&[("doe", "reindeer"), ("dog", "cat"), ("dog", "puppy")]);
+        assert_eq!(updated, root_of(&[("doe", "reindeer"), ("dog", "puppy")]));
+    }
+
+    #[test]
+    fn test_remove_to_empty() {
+        let entries = [
+            ("do", "verb"),
+            ("dog", "puppy"),
+            ("doge", "coin"),
+            ("dogglesworth", "cat"),
+            ("horse", "stallion"),
+        ];
+
+        let store = MemoryNodeStore::default();
+        let mut trie = Trie::with_store(empty_root(), &store);
+        for &(key, value) in &entries {
+            trie.insert(&bytes(key), bytes(value)).unwrap();
+        }
+        let root = trie.commit().unwrap();
+
+        // Removing keys one at a time gives the root of the remaining keys, until the trie is
+        // empty again.
+        let mut trie = Trie::with_store(root, &store);
+        for (removed, &(key, _)) in entries.iter().enumerate() {
+            trie.remove(&bytes(key)).unwrap();
+            let root = trie.commit().unwrap();
+            assert_eq!(root, root_of(&entries[removed + 1..]));
+            trie = Trie::with_store(root, &store);
+        }
+        assert_eq!(trie.commit().unwrap(), empty_root());
+    }
+}